@@ -0,0 +1,126 @@
+use std::time;
+
+use error::DomoError;
+use messages::BenchRun;
+use peripheral::*;
+use transport::Transport;
+
+
+// Measure the link with every combination of clock speed and byte delay, by
+// reading the test register `transactions` times. Without speeds the current
+// clock speed is used. The clock speed and byte delay are restored afterwards.
+pub fn run<T: Transport>(peripheral: &mut Peripheral<T>,
+                         speeds: &[u32],
+                         delays: &[time::Duration],
+                         transactions: u32)
+                         -> Result<Vec<BenchRun>, DomoError> {
+    let original_delay = peripheral.byte_delay();
+    let mut original_speed = None;
+    let mut runs = Vec::new();
+    let mut error = None;
+
+    let speeds: Vec<Option<u32>> = if speeds.is_empty() {
+        vec![None]
+    } else {
+        speeds.iter().map(|speed| Some(*speed)).collect()
+    };
+    for speed in speeds {
+        if let Some(speed) = speed {
+            match peripheral.transport_mut().set_speed(speed) {
+                Ok(previous) => original_speed = original_speed.or(Some(previous)),
+                Err(err) => {
+                    error = Some(err);
+                    break;
+                }
+            }
+        }
+        for delay in delays {
+            peripheral.set_byte_delay(*delay);
+            runs.push(bench_one(peripheral, speed, *delay, transactions));
+        }
+    }
+
+    peripheral.set_byte_delay(original_delay);
+    if let Some(speed) = original_speed {
+        try!(peripheral.transport_mut().set_speed(speed));
+    }
+    match error {
+        Some(err) => Err(DomoError::from(err)),
+        None => Ok(runs),
+    }
+}
+
+fn bench_one<T: Transport>(peripheral: &mut Peripheral<T>,
+                           speed: Option<u32>,
+                           delay: time::Duration,
+                           transactions: u32)
+                           -> BenchRun {
+    // Start in sync, a previous (too fast) speed may have left garbage.
+    let _ = peripheral.resync();
+
+    let before = peripheral.stats().clone();
+    let mut latencies = Vec::new();
+    let mut failures = 0;
+    let start = time::Instant::now();
+    for _ in 0..transactions {
+        let started = time::Instant::now();
+        match peripheral.read_number(CMD_TEST, 2) {
+            Ok(TEST_VALUE) => {}
+            _ => failures += 1,
+        }
+        latencies.push(millis(started.elapsed()));
+    }
+    let elapsed = millis(start.elapsed());
+    let after = peripheral.stats();
+
+    latencies.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let crc_errors = after.crc_errors - before.crc_errors;
+    let framing_errors = after.framing_errors - before.framing_errors;
+    let frames = after.transactions - before.transactions;
+    BenchRun {
+        speed_hz: speed,
+        byte_delay_us: delay.as_secs() * 1000000 + delay.subsec_nanos() as u64 / 1000,
+        transactions: transactions,
+        per_second: transactions as f64 / elapsed * 1000.0,
+        latency_p50_ms: percentile(&latencies, 0.5),
+        latency_p90_ms: percentile(&latencies, 0.9),
+        latency_p99_ms: percentile(&latencies, 0.99),
+        latency_max_ms: percentile(&latencies, 1.0),
+        crc_errors: crc_errors,
+        framing_errors: framing_errors,
+        failures: failures,
+        error_rate: (crc_errors + framing_errors) as f64 / frames.max(1) as f64,
+    }
+}
+
+fn millis(duration: time::Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + duration.subsec_nanos() as f64 / 1000000.0
+}
+
+// Value below which the given fraction of the sorted values lies.
+fn percentile(sorted: &[f64], fraction: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    sorted[((sorted.len() - 1) as f64 * fraction).round() as usize]
+}
+
+#[test]
+fn test_bench() {
+    use emulator::Emulator;
+
+    let mut peripheral = Peripheral::new(Emulator::new());
+    let delays = [time::Duration::from_millis(0), time::Duration::from_millis(1)];
+    let runs = run(&mut peripheral, &[], &delays, 20).unwrap();
+    assert_eq!(runs.len(), 2);
+    assert_eq!(runs[0].speed_hz, None);
+    assert_eq!(runs[1].byte_delay_us, 1000);
+    for run in runs.iter() {
+        assert_eq!(run.failures, 0);
+        assert!(run.latency_p50_ms <= run.latency_max_ms);
+    }
+    assert!(runs[0].per_second > runs[1].per_second);
+    assert_eq!(peripheral.byte_delay(), time::Duration::from_millis(1));
+
+    assert!(run(&mut peripheral, &[1000000], &delays, 20).is_err());
+}
@@ -0,0 +1,394 @@
+use std::{fmt, time};
+use std::io::{self, Write};
+
+use chrono::{DateTime, Local};
+
+use capabilities::*;
+use emulator::{Emulator, Faults};
+use error::DomoError;
+use lock::BusLock;
+use messages::*;
+use peripheral::*;
+use spidev::{self, SpidevOptions};
+use thermistor;
+use trace::{ReplayTransport, TraceTransport};
+use transport::{Transport, SpiTransport};
+use uart::{self, UartTransport};
+
+// How long to wait for another process to finish using the bus.
+const DEFAULT_LOCK_TIMEOUT_MS: u64 = 2000;
+
+pub fn decode_temp(value: u32) -> f64 {
+    // Value holds temperature in centidegrees, where 0 equals -55°C.
+    // Convert this value to regular °C readings.
+    ((value as i32 - 5500) as f64) / 100.0
+}
+
+// Open the transport selected in the config file. The default is the SPI device
+// at `spidev_path`, "serial" uses a UART and "emulator" runs against a software
+// model of the AVR.
+fn open_transport(config: &PeripheralConfig,
+                  spidev_path: &str)
+                  -> Result<Box<Transport + Send>, DomoError> {
+    match config.transport.as_ref().map(|s| s.as_str()).unwrap_or("spi") {
+        "spi" => {
+            let path = match config.device {
+                Some(ref path) => path.as_str(),
+                None => spidev_path,
+            };
+            Ok(Box::new(try!(SpiTransport::open(path, &try!(spi_options(config))))))
+        }
+        "serial" => {
+            let path = match config.device {
+                Some(ref path) => path.as_str(),
+                None => {
+                    return Err(DomoError::Config("no device configured for serial transport"
+                        .to_string()))
+                }
+            };
+            let baud_rate = config.baud_rate.unwrap_or(uart::DEFAULT_BAUD_RATE);
+            Ok(Box::new(try!(UartTransport::open(path, baud_rate))))
+        }
+        "emulator" => {
+            let mut emulator = Emulator::new();
+            emulator.set_faults(Faults {
+                crc_error_every: config.emulator_crc_error_every.unwrap_or(0),
+                dropped_write_every: config.emulator_dropped_write_every.unwrap_or(0),
+                desync_every: config.emulator_desync_every.unwrap_or(0),
+                button_interval: config.emulator_button_interval_ms
+                    .map(time::Duration::from_millis),
+            });
+            Ok(Box::new(emulator))
+        }
+        "replay" => {
+            let path = match config.device {
+                Some(ref path) => path.as_str(),
+                None => {
+                    return Err(DomoError::Config("no trace file configured for replay".to_string()))
+                }
+            };
+            Ok(Box::new(try!(ReplayTransport::open(path))))
+        }
+        name => Err(DomoError::Config(format!("unknown transport: {}", name))),
+    }
+}
+
+// Open the transport and record all traffic when a trace file is configured.
+fn open_traced_transport(config: &PeripheralConfig,
+                         spidev_path: &str)
+                         -> Result<Box<Transport + Send>, DomoError> {
+    let transport = try!(open_transport(config, spidev_path));
+    match config.trace {
+        Some(ref path) => Ok(Box::new(try!(TraceTransport::create(transport, path)))),
+        None => Ok(transport),
+    }
+}
+
+// SPI bus settings from the config file. Settings that aren't configured are
+// left at what the driver uses.
+fn spi_options(config: &PeripheralConfig) -> Result<SpidevOptions, DomoError> {
+    let mut options = SpidevOptions::new();
+    if let Some(speed_hz) = config.spi_speed_hz {
+        options.max_speed_hz(speed_hz);
+    }
+    if let Some(mode) = config.spi_mode {
+        options.mode(match mode {
+            0 => spidev::SPI_MODE_0,
+            1 => spidev::SPI_MODE_1,
+            2 => spidev::SPI_MODE_2,
+            3 => spidev::SPI_MODE_3,
+            _ => return Err(DomoError::Config(format!("invalid SPI mode: {}", mode))),
+        });
+    }
+    if let Some(bits_per_word) = config.spi_bits_per_word {
+        options.bits_per_word(bits_per_word);
+    }
+    Ok(options)
+}
+
+// File to lock while talking to the peripheral: the configured lock file or
+// else the SPI device. Serial ports are opened exclusively already.
+fn bus_lock_path(config: &PeripheralConfig, spidev_path: &str) -> Option<String> {
+    if let Some(ref path) = config.lock_file {
+        return Some(path.clone());
+    }
+    match config.transport.as_ref().map(|s| s.as_str()).unwrap_or("spi") {
+        "spi" => Some(config.device.clone().unwrap_or(spidev_path.to_string())),
+        _ => None,
+    }
+}
+
+fn retry_policy(config: &PeripheralConfig) -> RetryPolicy {
+    let mut policy = RetryPolicy::default();
+    if let Some(retries) = config.retries {
+        policy.retries = retries;
+    }
+    if let Some(backoff) = config.retry_backoff_ms {
+        policy.backoff = time::Duration::from_millis(backoff);
+    }
+    if let Some(resync_after) = config.resync_after {
+        policy.resync_after = resync_after;
+    }
+    policy
+}
+
+// A temperature with the calibration of the sensor (temp_offset and
+// temp_gain) applied, and the value as read before calibration.
+#[derive(Clone, Copy, Debug)]
+pub struct Temperature {
+    pub celsius: f64,
+    pub uncorrected: f64,
+}
+
+impl fmt::Display for Temperature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.celsius == self.uncorrected {
+            write!(f, "{:.2}°C", self.celsius)
+        } else {
+            write!(f, "{:.2}°C (uncorrected {:.2}°C)", self.celsius, self.uncorrected)
+        }
+    }
+}
+
+// One AVR connected to this controller, with its sensors and actuators.
+pub struct Board {
+    pub name: String,
+    config: PeripheralConfig,
+    pub peripheral: Peripheral<Box<Transport + Send>>,
+    pub capabilities: Capabilities,
+    pub color: Color,
+    temp_b_coefficient: Option<f64>,
+    temp_nominal_r: Option<f64>,
+    temp_series_resistor: Option<f64>,
+    // Steinhart–Hart coefficients from the config, used instead of the
+    // β-model when set.
+    temp_steinhart_hart: Option<(f64, f64, f64)>,
+    // Peripheral error count at the last time the stats were printed.
+    pub reported_errors: u64,
+    // Last logged temperature and when it was measured.
+    pub last_temp: Option<(f64, DateTime<Local>)>,
+}
+
+impl Board {
+    pub fn open(config: PeripheralConfig, spidev_path: &str) -> Result<Board, DomoError> {
+        let mut peripheral = Peripheral::new(try!(open_traced_transport(&config, spidev_path)));
+        peripheral.set_retry_policy(retry_policy(&config));
+        peripheral.set_verify_writes(config.verify_writes.unwrap_or(false));
+        peripheral.set_batch_transfers(config.batch_transfers.unwrap_or(false));
+        if let Some(delay_us) = config.byte_delay_us {
+            peripheral.set_byte_delay(time::Duration::new(delay_us / 1000000,
+                                                          (delay_us % 1000000) as u32 * 1000));
+        }
+        let steinhart_hart = match (config.temp_sh_a, config.temp_sh_b, config.temp_sh_c) {
+            (Some(a), Some(b), Some(c)) => Some((a, b, c)),
+            (None, None, None) => None,
+            _ => {
+                return Err(DomoError::Config("temp_sh_a, temp_sh_b and temp_sh_c must be set \
+                                              together"
+                    .to_string()))
+            }
+        };
+        if config.temp_gain.map_or(false, |gain| !(gain > 0.0)) {
+            return Err(DomoError::Config("temp_gain must be positive".to_string()));
+        }
+        if let Some(path) = bus_lock_path(&config, spidev_path) {
+            let timeout = config.lock_timeout_ms.unwrap_or(DEFAULT_LOCK_TIMEOUT_MS);
+            peripheral.set_bus_lock(try!(BusLock::open(&path,
+                                                       time::Duration::from_millis(timeout))));
+        }
+
+        Ok(Board {
+            name: config.name.clone(),
+            config: config,
+            peripheral: peripheral,
+            capabilities: Capabilities::legacy(),
+            color: Color::new(),
+            temp_b_coefficient: None,
+            temp_nominal_r: None,
+            temp_series_resistor: None,
+            temp_steinhart_hart: steinhart_hart,
+            reported_errors: 0,
+            last_temp: None,
+        })
+    }
+
+    // Name of a sensor or actuator on this board as known to the server. The
+    // name is prefixed with the board name, unless the board is unnamed.
+    pub fn sensor_name(&self, sensor: &str) -> String {
+        if self.name.is_empty() {
+            sensor.to_string()
+        } else {
+            format!("{}/{}", self.name, sensor)
+        }
+    }
+
+    // GPIO number of the interrupt line of this board, if it has one.
+    pub fn irq_gpio(&self) -> Option<u32> {
+        self.config.irq_gpio
+    }
+
+    // Find out which registers the firmware supports, so only existing sensors
+    // and actuators are used. Problems are printed to stderr, to keep them out
+    // of the output of commands.
+    pub fn discover(&mut self) {
+        let prefix = if self.name.is_empty() {
+            String::new()
+        } else {
+            format!("{}: ", self.name)
+        };
+        let mut stderr = io::stderr();
+        let _ = match self.peripheral.discover() {
+            Ok(capabilities) => {
+                let result = if capabilities.version == 0 {
+                    writeln!(stderr,
+                             "{}Peripheral does not support capability discovery, assuming all \
+                              registers exist.",
+                             prefix)
+                } else if capabilities.version != PROTOCOL_VERSION {
+                    writeln!(stderr,
+                             "{}WARNING: protocol version mismatch (host: {}, firmware: {})",
+                             prefix,
+                             PROTOCOL_VERSION,
+                             capabilities.version)
+                } else {
+                    Ok(())
+                };
+                self.capabilities = capabilities;
+                result
+            }
+            Err(err) => writeln!(stderr, "{}WARNING: capability discovery failed: {}", prefix, err),
+        };
+    }
+
+    // Read a register in a single transfer (see Peripheral::run_batch), so
+    // the board is only locked for a short time while polling.
+    pub fn poll(&mut self, cmd: u8, length: u8) -> Result<u32, DomoError> {
+        let mut results = try!(self.peripheral.run_batch(&[BatchOp::Get {
+                                                               cmd: cmd,
+                                                               length: length,
+                                                           }]));
+        results.pop().unwrap()
+    }
+
+    // Apply the calibration of this sensor to a temperature.
+    pub fn correct_temp(&self, celsius: f64) -> Temperature {
+        Temperature {
+            celsius: celsius * self.config.temp_gain.unwrap_or(1.0) +
+                     self.config.temp_offset.unwrap_or(0.0),
+            uncorrected: celsius,
+        }
+    }
+
+    // Read a temperature calculated by the AVR: CMD_TEMP_AVG or CMD_TEMP_NOW.
+    pub fn read_temp(&mut self, cmd: u8) -> Result<Temperature, DomoError> {
+        let value = try!(self.peripheral.read_number(cmd, 2));
+        Ok(self.correct_temp(decode_temp(value)))
+    }
+
+    pub fn read_temp_raw(&mut self) -> Result<Temperature, DomoError> {
+        let raw_value = try!(self.peripheral.read_number(CMD_TEMP_RAW, 4));
+        let celsius = try!(self.raw_to_celsius(raw_value, 10));
+        Ok(self.correct_temp(celsius))
+    }
+
+    pub fn read_temp_rsum(&mut self) -> Result<Temperature, DomoError> {
+        let raw_value = try!(self.peripheral.read_number(CMD_TEMP_RSUM, 4));
+        let celsius = try!(self.raw_to_celsius(raw_value, 18));
+        Ok(self.correct_temp(celsius))
+    }
+
+    fn get_temp_b_coefficient(&mut self) -> Result<f64, DomoError> {
+        Ok(match self.config.temp_b_coefficient {
+            Some(val) => val,
+            None => match self.temp_b_coefficient {
+                Some(val) => val,
+                None => {
+                    let b_coefficient = try!(self.peripheral.read_number(CMD_TEMP_BCOE, 2)) as f64;
+                    self.temp_b_coefficient = Some(b_coefficient);
+                    b_coefficient // return
+                }
+            }
+        })
+    }
+
+    fn get_temp_nominal_r(&mut self) -> Result<f64, DomoError> {
+        Ok(match self.config.temp_nominal_r {
+            Some(val) => val,
+            None => match self.temp_nominal_r {
+                Some(val) => val,
+                None => {
+                    let nominal_r = try!(self.peripheral.read_number(CMD_TEMP_NRES, 2)) as f64;
+                    self.temp_nominal_r = Some(nominal_r);
+                    nominal_r // return
+                }
+            }
+        })
+    }
+
+    fn get_temp_series_resistor(&mut self) -> Result<f64, DomoError> {
+        Ok(match self.config.temp_series_resistor {
+            Some(val) => val,
+            None => match self.temp_series_resistor {
+                Some(val) => val,
+                None => {
+                    let series_resistor = try!(self.peripheral.read_number(CMD_TEMP_SRES, 2)) as f64;
+                    self.temp_series_resistor = Some(series_resistor);
+                    series_resistor // return
+                }
+            }
+        })
+    }
+
+    // Resistance of the NTC, from the sum of raw ADC readings (CMD_TEMP_RSUM).
+    pub fn read_temp_resistance(&mut self) -> Result<f64, DomoError> {
+        let raw_value = try!(self.peripheral.read_number(CMD_TEMP_RSUM, 4));
+        self.raw_to_resistance(raw_value, 18)
+    }
+
+    fn raw_to_resistance(&mut self, value: u32, bits: u32) -> Result<f64, DomoError> {
+        let series_resistor: f64 = try!(self.get_temp_series_resistor()); // 10kΩ series resistor
+
+        // convert value to range 0..1, where 0.5 means r=series_resistor
+        let fvalue: f64 = value as f64 / (1 << bits) as f64;
+        Ok(thermistor::resistance(fvalue, series_resistor))
+    }
+
+    fn raw_to_celsius(&mut self, value: u32, bits: u32) -> Result<f64, DomoError> {
+        let r = try!(self.raw_to_resistance(value, bits));
+        if let Some(coefficients) = self.temp_steinhart_hart {
+            return Ok(thermistor::steinhart_hart_to_celsius(r, coefficients));
+        }
+        let b_coefficient = try!(self.get_temp_b_coefficient());
+        let r0: f64 = try!(self.get_temp_nominal_r()); // 10kΩ at 25°C
+        Ok(thermistor::beta_to_celsius(r, r0, b_coefficient))
+    }
+}
+
+#[cfg(test)]
+impl Board {
+    // Board running against the emulator without byte delay, for tests.
+    // `settings` are extra members of the config object, in JSON.
+    pub fn emulated(name: &str, settings: &str) -> Board {
+        use serde_json;
+
+        let mut config = format!(r#"{{"name": "{}", "transport": "emulator", "byte_delay_us": 0"#,
+                                 name);
+        if !settings.is_empty() {
+            config.push_str(", ");
+            config.push_str(settings);
+        }
+        config.push('}');
+        Board::open(serde_json::from_str(&config).unwrap(), "").unwrap()
+    }
+}
+
+#[test]
+fn test_temp_correction() {
+    let mut board = Board::emulated("", r#""temp_offset": -1.5, "temp_gain": 1.1"#);
+    let temp = board.read_temp(CMD_TEMP_AVG).unwrap();
+    assert_eq!(temp.uncorrected, 21.5);
+    assert!((temp.celsius - (21.5 * 1.1 - 1.5)).abs() < 1e-9);
+    let temp = board.read_temp_rsum().unwrap();
+    assert!((temp.celsius - (temp.uncorrected * 1.1 - 1.5)).abs() < 1e-9);
+    assert_eq!(board.correct_temp(20.0).to_string(), "20.50°C (uncorrected 20.00°C)");
+}
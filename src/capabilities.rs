@@ -0,0 +1,159 @@
+use peripheral::*;
+
+
+// Version of the peripheral protocol implemented by this program.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+// Upper limit for the register list, command IDs are only 6 bits.
+pub const MAX_REGISTERS: u32 = 64;
+
+// What a register means, so the host knows what to do with it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegisterKind {
+    Unknown,
+    Temperature, // centidegrees, where 0 equals -55°C
+    TemperatureRaw, // raw NTC reading
+    Color,
+    Constant,
+    Test,
+    Text,
+    Bootloader,
+    Events,
+}
+
+impl RegisterKind {
+    fn from_byte(value: u8) -> RegisterKind {
+        match value {
+            1 => RegisterKind::Temperature,
+            2 => RegisterKind::TemperatureRaw,
+            3 => RegisterKind::Color,
+            4 => RegisterKind::Constant,
+            5 => RegisterKind::Test,
+            6 => RegisterKind::Text,
+            7 => RegisterKind::Bootloader,
+            8 => RegisterKind::Events,
+            _ => RegisterKind::Unknown,
+        }
+    }
+
+    fn to_byte(&self) -> u8 {
+        match *self {
+            RegisterKind::Unknown => 0,
+            RegisterKind::Temperature => 1,
+            RegisterKind::TemperatureRaw => 2,
+            RegisterKind::Color => 3,
+            RegisterKind::Constant => 4,
+            RegisterKind::Test => 5,
+            RegisterKind::Text => 6,
+            RegisterKind::Bootloader => 7,
+            RegisterKind::Events => 8,
+        }
+    }
+}
+
+// Register descriptor as sent by the firmware in CMD_REGISTER_INFO, encoded
+// in 4 bytes (LSB first): id, width in bytes (0 for variable-length registers
+// read with extended frames), flags, kind.
+#[derive(Clone, Copy, Debug)]
+pub struct RegisterInfo {
+    pub id: u8,
+    pub width: u8,
+    pub readable: bool,
+    pub writable: bool,
+    pub kind: RegisterKind,
+}
+
+const FLAG_READABLE: u8 = 0b01;
+const FLAG_WRITABLE: u8 = 0b10;
+
+impl RegisterInfo {
+    // Decode a descriptor. A register ID of 0 marks the end of the list.
+    pub fn decode(value: u32) -> Option<RegisterInfo> {
+        let id = value as u8;
+        if id == 0 {
+            return None;
+        }
+        let flags = (value >> 16) as u8;
+        Some(RegisterInfo {
+            id: id,
+            width: (value >> 8) as u8,
+            readable: flags & FLAG_READABLE != 0,
+            writable: flags & FLAG_WRITABLE != 0,
+            kind: RegisterKind::from_byte((value >> 24) as u8),
+        })
+    }
+
+    pub fn encode(&self) -> u32 {
+        let mut flags = 0;
+        if self.readable {
+            flags |= FLAG_READABLE;
+        }
+        if self.writable {
+            flags |= FLAG_WRITABLE;
+        }
+        self.id as u32 | (self.width as u32) << 8 | (flags as u32) << 16 |
+        (self.kind.to_byte() as u32) << 24
+    }
+}
+
+// Protocol version and registers supported by the firmware.
+#[derive(Clone, Debug)]
+pub struct Capabilities {
+    // Protocol version, 0 for firmware without capability discovery.
+    pub version: u16,
+    pub registers: Vec<RegisterInfo>,
+}
+
+impl Capabilities {
+    // Capabilities assumed for firmware that doesn't support discovery.
+    pub fn legacy() -> Self {
+        let register = |id, width, writable, kind| {
+            RegisterInfo {
+                id: id,
+                width: width,
+                readable: true,
+                writable: writable,
+                kind: kind,
+            }
+        };
+        Capabilities {
+            version: 0,
+            registers: vec![
+                register(CMD_COLOR, 4, true, RegisterKind::Color),
+                register(CMD_TEMP_NOW, 2, false, RegisterKind::Temperature),
+                register(CMD_TEMP_AVG, 2, false, RegisterKind::Temperature),
+                register(CMD_TEMP_RAW, 4, false, RegisterKind::TemperatureRaw),
+                register(CMD_TEMP_RSUM, 4, false, RegisterKind::TemperatureRaw),
+                register(CMD_TEMP_SRES, 2, false, RegisterKind::Constant),
+                register(CMD_TEMP_NRES, 2, false, RegisterKind::Constant),
+                register(CMD_TEMP_BCOE, 2, false, RegisterKind::Constant),
+                register(CMD_TEST, 4, false, RegisterKind::Test),
+            ],
+        }
+    }
+
+    pub fn get(&self, id: u8) -> Option<&RegisterInfo> {
+        self.registers.iter().find(|r| r.id == id)
+    }
+
+    pub fn can_read(&self, id: u8) -> bool {
+        self.get(id).map(|r| r.readable).unwrap_or(false)
+    }
+
+    pub fn can_write(&self, id: u8) -> bool {
+        self.get(id).map(|r| r.writable).unwrap_or(false)
+    }
+}
+
+#[test]
+fn test_register_info() {
+    for info in Capabilities::legacy().registers.iter() {
+        let decoded = RegisterInfo::decode(info.encode()).unwrap();
+        assert_eq!(decoded.id, info.id);
+        assert_eq!(decoded.width, info.width);
+        assert_eq!(decoded.readable, info.readable);
+        assert_eq!(decoded.writable, info.writable);
+        assert_eq!(decoded.kind, info.kind);
+    }
+    assert!(RegisterInfo::decode(0).is_none());
+}
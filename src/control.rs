@@ -0,0 +1,117 @@
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::thread;
+
+use serde_json;
+
+use error::DomoError;
+use messages::*;
+
+
+// The directory is only writable by root, so no other user can create the
+// socket before the daemon does and answer commands in its place.
+pub const DEFAULT_SOCKET_PATH: &'static str = "/run/domoc/domoc.sock";
+
+// Send a command to the running daemon over its control socket. Each request
+// and response is a single line of JSON. Returns None when no daemon is
+// running.
+pub fn send(path: &str, args: &[String]) -> Option<Result<ControlResponse, DomoError>> {
+    let stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(_) => return None,
+    };
+    Some(request(stream, args))
+}
+
+fn request(mut stream: UnixStream, args: &[String]) -> Result<ControlResponse, DomoError> {
+    let request = ControlRequest { args: args.to_vec() };
+    try!(writeln!(stream, "{}", serde_json::to_string(&request).unwrap()));
+
+    let mut line = String::new();
+    try!(BufReader::new(stream).read_line(&mut line));
+    serde_json::from_str(&line)
+        .map_err(|err| DomoError::Protocol(format!("invalid response from daemon: {}", err)))
+}
+
+// Whether a daemon is listening on the control socket.
+pub fn is_running(path: &str) -> bool {
+    UnixStream::connect(path).is_ok()
+}
+
+// Listen on the control socket and run every command received with `handler`,
+// which writes the output of the command and returns its exit status.
+pub fn serve<F>(path: &str, handler: F) -> Result<(), io::Error>
+    where F: Fn(&[String], &mut Write) -> i32 + Send + Sync + 'static
+{
+    if is_running(path) {
+        return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                                  format!("another daemon is listening on {}", path)));
+    }
+    if let Some(dir) = Path::new(path).parent() {
+        try!(fs::create_dir_all(dir));
+    }
+    // Remove the socket of a daemon that didn't exit cleanly.
+    let _ = fs::remove_file(path);
+    let listener = try!(UnixListener::bind(path));
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| handle_client(stream, &handler));
+            if let Err(err) = result {
+                println!("WARNING: control socket: {}", err);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle_client<F>(stream: UnixStream, handler: &F) -> Result<(), io::Error>
+    where F: Fn(&[String], &mut Write) -> i32
+{
+    let mut line = String::new();
+    try!(BufReader::new(try!(stream.try_clone())).read_line(&mut line));
+    if line.is_empty() {
+        // Only checking whether the daemon is running.
+        return Ok(());
+    }
+    let request: ControlRequest = match serde_json::from_str(&line) {
+        Ok(request) => request,
+        Err(err) => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("invalid request: {}", err)))
+        }
+    };
+
+    let mut output = Vec::new();
+    let status = handler(&request.args, &mut output);
+    let response = ControlResponse {
+        status: status,
+        output: String::from_utf8_lossy(&output).into_owned(),
+    };
+    let mut stream = stream;
+    writeln!(stream, "{}", serde_json::to_string(&response).unwrap())
+}
+
+#[test]
+fn test_control_socket() {
+    use std::env;
+
+    let path = env::temp_dir().join("domoc-test-control.sock");
+    let path = path.to_str().unwrap();
+    serve(path, |args, out| {
+            writeln!(out, "{}", args.join(" ")).unwrap();
+            args.len() as i32
+        })
+        .unwrap();
+
+    let response = send(path, &["color".to_string(), "ff".to_string()]).unwrap().unwrap();
+    assert_eq!(response.status, 2);
+    assert_eq!(response.output, "color ff\n");
+
+    let _ = fs::remove_file(path);
+    assert!(send(path, &[]).is_none());
+}
@@ -0,0 +1,193 @@
+use board::Board;
+use messages::DiagnoseCheck;
+use peripheral::*;
+
+
+// Number of resyncs and test register reads done to exercise the link.
+const RESYNCS: u32 = 5;
+const TEST_READS: u32 = 50;
+
+// Highest acceptable fraction of transactions with a CRC or framing error.
+const MAX_ERROR_RATE: f64 = 0.01;
+
+// Plausible range of the thermistor constants: resistors of 1kΩ to 65kΩ (the
+// registers are 2 bytes wide) and a β coefficient of a common NTC.
+const RESISTOR_RANGE: (f64, f64) = (1000.0, 65535.0);
+const B_COEFFICIENT_RANGE: (f64, f64) = (2000.0, 5000.0);
+
+// Temperatures must be within this range, and all ways of calculating the
+// temperature must agree within TEMP_TOLERANCE.
+const TEMP_RANGE: (f64, f64) = (-40.0, 85.0);
+const TEMP_TOLERANCE: f64 = 2.0;
+
+// Color written to test the color register. The original color is restored
+// afterwards.
+const TEST_COLOR: u32 = 0x4148ffff;
+
+// Run all health checks on a board, e.g. after installing it. A failing check
+// doesn't stop the others, so the report is always complete.
+pub fn run(board: &mut Board) -> Vec<DiagnoseCheck> {
+    let mut checks = Vec::new();
+    let before = board.peripheral.stats().clone();
+
+    add(&mut checks, "resync", check_resync(board));
+    add(&mut checks,
+        "test register, 2 bytes",
+        check_test_register(board, 2, Some(TEST_VALUE)));
+    // Only the 2-byte value of the test register is defined, a 4-byte read
+    // must just give the same value every time.
+    add(&mut checks, "test register, 4 bytes", check_test_register(board, 4, None));
+
+    let after = board.peripheral.stats().clone();
+    let transactions = after.transactions - before.transactions;
+    let crc_errors = after.crc_errors - before.crc_errors;
+    let framing_errors = after.framing_errors - before.framing_errors;
+    let rate = (crc_errors + framing_errors) as f64 / transactions.max(1) as f64;
+    let detail = format!("{} CRC and {} framing errors in {} transactions, {:.1}%",
+                         crc_errors,
+                         framing_errors,
+                         transactions,
+                         rate * 100.0);
+    add(&mut checks,
+        "link errors",
+        if rate <= MAX_ERROR_RATE { Ok(detail) } else { Err(detail) });
+
+    add(&mut checks, "thermistor constants", check_thermistor(board));
+    add(&mut checks, "temperature", check_temperature(board));
+    add(&mut checks, "color read-back", check_color(board));
+    checks
+}
+
+fn add(checks: &mut Vec<DiagnoseCheck>, name: &str, result: Result<String, String>) {
+    let (ok, detail) = match result {
+        Ok(detail) => (true, detail),
+        Err(detail) => (false, detail),
+    };
+    checks.push(DiagnoseCheck {
+        name: name.to_string(),
+        ok: ok,
+        detail: detail,
+    });
+}
+
+fn check_resync(board: &mut Board) -> Result<String, String> {
+    for i in 0..RESYNCS {
+        if let Err(err) = board.peripheral.resync() {
+            return Err(format!("attempt {} of {}: {}", i + 1, RESYNCS, err));
+        }
+    }
+    Ok(format!("{} times", RESYNCS))
+}
+
+// Read the test register many times. Without an expected value, the value of
+// the first read is expected.
+fn check_test_register(board: &mut Board,
+                       width: u8,
+                       mut expected: Option<u32>)
+                       -> Result<String, String> {
+    let mut wrong = 0;
+    let mut errors = 0;
+    for _ in 0..TEST_READS {
+        match board.peripheral.read_number(CMD_TEST, width) {
+            Ok(val) if expected.is_none() => expected = Some(val),
+            Ok(val) if Some(val) == expected => {}
+            Ok(_) => wrong += 1,
+            Err(_) => errors += 1,
+        }
+    }
+    let detail = format!("{} reads of {}, {} wrong values, {} errors",
+                         TEST_READS,
+                         expected.map_or("nothing".to_string(), |val| format!("{:#x}", val)),
+                         wrong,
+                         errors);
+    if wrong == 0 && errors == 0 {
+        Ok(detail)
+    } else {
+        Err(detail)
+    }
+}
+
+fn check_thermistor(board: &mut Board) -> Result<String, String> {
+    let series_resistor = try!(read_constant(board, CMD_TEMP_SRES));
+    let nominal_r = try!(read_constant(board, CMD_TEMP_NRES));
+    let b_coefficient = try!(read_constant(board, CMD_TEMP_BCOE));
+    let detail = format!("series {}Ω, nominal {}Ω, B {}",
+                         series_resistor,
+                         nominal_r,
+                         b_coefficient);
+    if in_range(series_resistor, RESISTOR_RANGE) && in_range(nominal_r, RESISTOR_RANGE) &&
+       in_range(b_coefficient, B_COEFFICIENT_RANGE) {
+        Ok(detail)
+    } else {
+        Err(format!("out of range: {}", detail))
+    }
+}
+
+fn read_constant(board: &mut Board, cmd: u8) -> Result<f64, String> {
+    board.peripheral
+        .read_number(cmd, 2)
+        .map(|val| val as f64)
+        .map_err(|err| format!("register {:02x}: {}", cmd, err))
+}
+
+fn check_temperature(board: &mut Board) -> Result<String, String> {
+    let now = try!(board.read_temp(CMD_TEMP_NOW).map_err(|err| format!("temp now: {}", err)))
+        .celsius;
+    let raw = try!(board.read_temp_raw().map_err(|err| format!("temp raw: {}", err))).celsius;
+    let rsum = try!(board.read_temp_rsum().map_err(|err| format!("temp rsum: {}", err))).celsius;
+    let detail = format!("now {:.2}°C, raw {:.2}°C, rsum {:.2}°C", now, raw, rsum);
+
+    let temps = [now, raw, rsum];
+    if !temps.iter().all(|temp| in_range(*temp, TEMP_RANGE)) {
+        return Err(format!("out of range: {}", detail));
+    }
+    let min = temps.iter().cloned().fold(now, f64::min);
+    let max = temps.iter().cloned().fold(now, f64::max);
+    if max - min > TEMP_TOLERANCE {
+        return Err(format!("differ by {:.2}°C: {}", max - min, detail));
+    }
+    Ok(detail)
+}
+
+fn check_color(board: &mut Board) -> Result<String, String> {
+    let original = try!(board.peripheral
+        .read_number(CMD_COLOR, 4)
+        .map_err(|err| format!("read: {}", err)));
+    // Make sure the test color differs from the current color.
+    let test_color = if original == TEST_COLOR {
+        TEST_COLOR ^ 0xff
+    } else {
+        TEST_COLOR
+    };
+    let result = board.peripheral
+        .write_number(CMD_COLOR, 4, test_color)
+        .map_err(|err| format!("write: {}", err))
+        .and_then(|_| {
+            board.peripheral.read_number(CMD_COLOR, 4).map_err(|err| format!("read: {}", err))
+        });
+    let restored = board.peripheral.write_number(CMD_COLOR, 4, original);
+    match result {
+        Ok(val) if val == test_color => {}
+        Ok(val) => return Err(format!("wrote {:08x}, read {:08x}", test_color, val)),
+        Err(msg) => return Err(msg),
+    }
+    if let Err(err) = restored {
+        return Err(format!("could not restore color {:08x}: {}", original, err));
+    }
+    Ok(format!("{:08x}", test_color))
+}
+
+fn in_range(value: f64, range: (f64, f64)) -> bool {
+    value >= range.0 && value <= range.1
+}
+
+#[test]
+fn test_diagnose() {
+    let mut board = Board::emulated("", "");
+    let checks = run(&mut board);
+    assert_eq!(checks.len(), 7);
+    for check in checks {
+        assert!(check.ok, "{}: {}", check.name, check.detail);
+    }
+    assert_eq!(board.peripheral.read_number(CMD_COLOR, 4).unwrap(), 0);
+}
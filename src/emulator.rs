@@ -0,0 +1,632 @@
+use std::collections::{HashMap, VecDeque};
+use std::{io, time};
+
+use crc8::Crc8;
+
+use capabilities::*;
+use flash::{APP_FLASH_SIZE, BOOT_ENTER, PAGE_SIZE};
+use peripheral::*;
+use transport::Transport;
+
+
+// Byte shifted out by the AVR when it has nothing to send.
+const IDLE_BYTE: u8 = 0x00;
+
+// Thermistor constants reported by the emulated board.
+const SERIES_RESISTOR: u32 = 10000;
+const NOMINAL_R: u32 = 10000;
+const B_COEFFICIENT: u32 = 3950;
+
+// Number of bytes swallowed by a desync from Faults::desync_every.
+const DESYNC_BYTES: usize = 3;
+
+// Number of bytes missed while jumping between the application and the
+// bootloader.
+const BOOT_JUMP_BYTES: usize = 8;
+
+// Faults the emulator injects by itself, so error handling can be tried end to
+// end without hardware. Counts of 0 disable a fault.
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    // Send a wrong CRC with the response to every nth command.
+    pub crc_error_every: u32,
+    // Ignore every nth command if it is a setter.
+    pub dropped_write_every: u32,
+    // Lose sync with the host at every nth command.
+    pub desync_every: u32,
+    // Change the color as if the button was pressed, at this interval.
+    pub button_interval: Option<time::Duration>,
+}
+
+enum State {
+    Idle,
+    // Receiving the payload and CRC of a setter command.
+    Receiving { rawcmd: u8, length: usize, buf: Vec<u8> },
+    // Received the first byte of an extended frame, waiting for the command.
+    ExtendedCommand { setter: bool },
+    // Waiting for the payload length of an extended setter.
+    ExtendedLength { cmd: u8 },
+    // Receiving the payload and CRC of an extended setter.
+    ReceivingExtended { length: usize, buf: Vec<u8> },
+    // Out of sync: the next n bytes are swallowed as if they were part of a
+    // frame the host doesn't know about.
+    Desynced(usize),
+}
+
+// Software model of the domo-avr firmware. It speaks the same framed protocol
+// as the real board, so Peripheral can be used without any hardware.
+pub struct Emulator {
+    registers: HashMap<u8, u32>,
+    // Variable-length registers, accessed with extended frames.
+    blobs: HashMap<u8, Vec<u8>>,
+    descriptors: Vec<RegisterInfo>,
+    flash: Vec<u8>,
+    state: State,
+    output: VecDeque<u8>,
+    crc8: Crc8,
+    crc_errors: usize,
+    dropped_writes: usize,
+    faults: Faults,
+    // Commands received, for the faults that happen every nth command.
+    commands: u32,
+    next_button_press: Option<time::Instant>,
+}
+
+impl Emulator {
+    pub fn new() -> Emulator {
+        let mut registers = HashMap::new();
+        registers.insert(CMD_PROTOCOL_VERSION, PROTOCOL_VERSION as u32);
+        registers.insert(CMD_REGISTER_INDEX, 0);
+        registers.insert(CMD_COLOR, 0);
+        registers.insert(CMD_EVENTS, 0);
+        registers.insert(CMD_TEMP_SRES, SERIES_RESISTOR);
+        registers.insert(CMD_TEMP_NRES, NOMINAL_R);
+        registers.insert(CMD_TEMP_BCOE, B_COEFFICIENT);
+        registers.insert(CMD_TEST, TEST_VALUE);
+        registers.insert(CMD_BOOT_CONTROL, 0);
+        registers.insert(CMD_BOOT_ADDRESS, 0);
+
+        let mut blobs = HashMap::new();
+        blobs.insert(CMD_FIRMWARE_VERSION, b"domo-avr emulator".to_vec());
+
+        let mut descriptors = Capabilities::legacy().registers;
+        descriptors.push(RegisterInfo {
+            id: CMD_FIRMWARE_VERSION,
+            width: 0,
+            readable: true,
+            writable: false,
+            kind: RegisterKind::Text,
+        });
+        descriptors.push(RegisterInfo {
+            id: CMD_EVENTS,
+            width: 2,
+            readable: true,
+            writable: false,
+            kind: RegisterKind::Events,
+        });
+        for &(id, width) in &[(CMD_BOOT_CONTROL, 2), (CMD_BOOT_ADDRESS, 4), (CMD_BOOT_PAGE, 0)] {
+            descriptors.push(RegisterInfo {
+                id: id,
+                width: width,
+                readable: true,
+                writable: true,
+                kind: RegisterKind::Bootloader,
+            });
+        }
+
+        let mut emulator = Emulator {
+            registers: registers,
+            blobs: blobs,
+            descriptors: descriptors,
+            flash: vec![0xff; APP_FLASH_SIZE],
+            state: State::Idle,
+            output: VecDeque::new(),
+            crc8: Crc8::create_msb(0x07),
+            crc_errors: 0,
+            dropped_writes: 0,
+            faults: Faults::default(),
+            commands: 0,
+            next_button_press: None,
+        };
+        emulator.set_temperature(21.5);
+        emulator
+    }
+
+    // Update all temperature registers as if the NTC measured this temperature.
+    pub fn set_temperature(&mut self, celsius: f64) {
+        // Inverse of the β-model used in Domo::raw_to_celsius.
+        let t0: f64 = 298.15;
+        let t = celsius + 273.15;
+        let r = NOMINAL_R as f64 * (B_COEFFICIENT as f64 * (1.0 / t - 1.0 / t0)).exp();
+        let fvalue = r / (r + SERIES_RESISTOR as f64);
+
+        let centidegrees = (celsius * 100.0).round() as i32 + 5500;
+        self.registers.insert(CMD_TEMP_NOW, centidegrees as u32);
+        self.registers.insert(CMD_TEMP_AVG, centidegrees as u32);
+        self.registers.insert(CMD_TEMP_RAW, (fvalue * (1 << 10) as f64).round() as u32);
+        self.registers.insert(CMD_TEMP_RSUM, (fvalue * (1 << 18) as f64).round() as u32);
+    }
+
+    // Inject these faults from now on.
+    pub fn set_faults(&mut self, faults: Faults) {
+        self.next_button_press = faults.button_interval.map(|interval| {
+            time::Instant::now() + interval
+        });
+        self.faults = faults;
+        self.commands = 0;
+    }
+
+    // Change the color as if it was changed with the buttons on the board.
+    pub fn press_button(&mut self, color: u32) {
+        self.registers.insert(CMD_COLOR, color);
+        *self.registers.get_mut(&CMD_EVENTS).unwrap() |= EVENT_COLOR;
+    }
+
+    // Send a wrong CRC with the next response.
+    pub fn inject_crc_error(&mut self) {
+        self.crc_errors += 1;
+    }
+
+    // Ignore the next setter command, as if its frame got corrupted.
+    pub fn inject_dropped_write(&mut self) {
+        self.dropped_writes += 1;
+    }
+
+    // Lose sync with the host: the next `bytes` bytes received are ignored.
+    pub fn inject_desync(&mut self, bytes: usize) {
+        self.output.clear();
+        self.state = State::Desynced(bytes);
+    }
+
+    // Inject the configured faults that are due at the start of a command.
+    fn inject_faults(&mut self) {
+        self.commands = self.commands.wrapping_add(1);
+        let commands = self.commands;
+        let due = |every: u32| every > 0 && commands % every == 0;
+        if due(self.faults.crc_error_every) {
+            self.inject_crc_error();
+        }
+        if due(self.faults.dropped_write_every) {
+            self.inject_dropped_write();
+        }
+        if due(self.faults.desync_every) {
+            self.inject_desync(DESYNC_BYTES);
+        }
+    }
+
+    // Press the button when the configured interval has passed, cycling
+    // through the hues.
+    fn press_button_when_due(&mut self) {
+        let (next, interval) = match (self.next_button_press, self.faults.button_interval) {
+            (Some(next), Some(interval)) => (next, interval),
+            _ => return,
+        };
+        if time::Instant::now() < next {
+            return;
+        }
+        let hue = (self.registers[&CMD_COLOR] >> 16 & 0xff) + 0x20;
+        self.press_button(0x0100ffff | (hue & 0xff) << 16);
+        self.next_button_press = Some(next + interval);
+    }
+
+    // Shift one byte in and one byte out, like the AVR SPI data register.
+    fn exchange(&mut self, input: u8) -> u8 {
+        self.press_button_when_due();
+        match self.output.pop_front() {
+            // Input is ignored while a response is being sent.
+            Some(c) => c,
+            None => {
+                self.receive(input);
+                IDLE_BYTE
+            }
+        }
+    }
+
+    // Receive one byte over a UART and return everything the AVR sends back.
+    #[cfg(test)]
+    pub fn receive_uart(&mut self, input: u8) -> Vec<u8> {
+        self.receive(input);
+        self.output.drain(..).collect()
+    }
+
+    fn receive(&mut self, input: u8) {
+        if let State::Idle = self.state {
+            self.inject_faults();
+        }
+        let state = ::std::mem::replace(&mut self.state, State::Idle);
+        self.state = match state {
+            State::Idle if input == EXT_GETTER => State::ExtendedCommand { setter: false },
+            State::Idle if input == EXT_SETTER => State::ExtendedCommand { setter: true },
+            State::Idle => {
+                let cmd = input & !TYPE_MASK;
+                match input & TYPE_MASK {
+                    TYPE_GETTER2 => self.respond(input, cmd, 2),
+                    TYPE_GETTER4 => self.respond(input, cmd, 4),
+                    TYPE_SETTER2 => State::Receiving { rawcmd: input, length: 2, buf: vec![input] },
+                    TYPE_SETTER4 => State::Receiving { rawcmd: input, length: 4, buf: vec![input] },
+                    _ => unreachable!(),
+                }
+            }
+            State::Receiving { rawcmd, length, mut buf } => {
+                buf.push(input);
+                if buf.len() < length + 2 {
+                    State::Receiving { rawcmd: rawcmd, length: length, buf: buf }
+                } else {
+                    let crc = self.crc8.calc(&buf, length as i32 + 1, 0);
+                    let mut next = State::Idle;
+                    if self.dropped_writes > 0 {
+                        self.dropped_writes -= 1;
+                    } else if crc == buf[length + 1] {
+                        let mut value: u32 = 0;
+                        for i in 0..length {
+                            value |= (buf[i + 1] as u32) << (i * 8);
+                        }
+                        let cmd = rawcmd & !TYPE_MASK;
+                        if self.registers.contains_key(&cmd) {
+                            let previous = self.registers.insert(cmd, value);
+                            if cmd == CMD_BOOT_CONTROL && previous != Some(value) {
+                                // The AVR doesn't listen while it jumps to
+                                // the bootloader or back.
+                                self.output.clear();
+                                next = State::Desynced(BOOT_JUMP_BYTES);
+                            }
+                        }
+                    }
+                    next
+                }
+            }
+            State::ExtendedCommand { setter: false } => self.respond_extended(input),
+            State::ExtendedCommand { setter: true } => State::ExtendedLength { cmd: input },
+            State::ExtendedLength { cmd } => {
+                State::ReceivingExtended {
+                    length: input as usize,
+                    buf: vec![EXT_SETTER, cmd, input],
+                }
+            }
+            State::ReceivingExtended { length, mut buf } => {
+                buf.push(input);
+                if buf.len() < length + 4 {
+                    State::ReceivingExtended { length: length, buf: buf }
+                } else {
+                    let crc = buf.pop().unwrap();
+                    if crc == self.crc8.calc(&buf, buf.len() as i32, 0) {
+                        let cmd = buf[1];
+                        let payload = buf.split_off(3);
+                        if cmd == CMD_BOOT_PAGE {
+                            self.write_page(&payload);
+                        } else if self.blobs.contains_key(&cmd) {
+                            self.blobs.insert(cmd, payload);
+                        } else if self.registers.contains_key(&cmd) && payload.len() <= 4 {
+                            let mut value: u32 = 0;
+                            for (i, c) in payload.iter().enumerate() {
+                                value |= (*c as u32) << (i * 8);
+                            }
+                            self.registers.insert(cmd, value);
+                        }
+                    }
+                    State::Idle
+                }
+            }
+            State::Desynced(1) => State::Idle,
+            State::Desynced(n) => State::Desynced(n - 1),
+        };
+    }
+
+    fn respond(&mut self, rawcmd: u8, cmd: u8, length: usize) -> State {
+        let value = if cmd == CMD_REGISTER_INFO {
+            let index = self.registers[&CMD_REGISTER_INDEX] as usize;
+            match self.descriptors.get(index) {
+                Some(info) => info.encode(),
+                None => 0, // end of list
+            }
+        } else {
+            match self.registers.get(&cmd) {
+                Some(value) => *value,
+                None => return State::Idle, // unknown command: no response
+            }
+        };
+
+        if cmd == CMD_EVENTS {
+            self.registers.insert(CMD_EVENTS, 0);
+        }
+
+        let mut buf = vec![rawcmd];
+        for i in 0..length {
+            buf.push((value >> (i * 8)) as u8);
+        }
+        let mut crc = self.crc8.calc(&buf, length as i32 + 1, 0);
+        if self.crc_errors > 0 {
+            self.crc_errors -= 1;
+            crc = !crc;
+        }
+        buf.push(crc);
+
+        self.output.push_back(0xff);
+        self.output.extend(&buf[1..]);
+        State::Idle
+    }
+
+    // Flash page at CMD_BOOT_ADDRESS, if it exists.
+    fn boot_page(&self) -> Option<(usize, usize)> {
+        let address = self.registers[&CMD_BOOT_ADDRESS] as usize;
+        if address % PAGE_SIZE != 0 || address + PAGE_SIZE > self.flash.len() {
+            return None;
+        }
+        Some((address, address + PAGE_SIZE))
+    }
+
+    // Program a page of flash, which only works in the bootloader.
+    fn write_page(&mut self, payload: &[u8]) {
+        if self.registers[&CMD_BOOT_CONTROL] != BOOT_ENTER || payload.len() != PAGE_SIZE {
+            return;
+        }
+        if let Some((start, end)) = self.boot_page() {
+            self.flash[start..end].copy_from_slice(payload);
+        }
+    }
+
+    fn respond_extended(&mut self, cmd: u8) -> State {
+        let payload = match self.blobs.get(&cmd) {
+            Some(payload) => payload.clone(),
+            None if cmd == CMD_BOOT_PAGE => {
+                match self.boot_page() {
+                    Some((start, end)) => self.flash[start..end].to_vec(),
+                    None => return State::Idle,
+                }
+            }
+            None => {
+                match self.registers.get(&cmd) {
+                    Some(value) => (0..4).map(|i| (value >> (i * 8)) as u8).collect(),
+                    None => return State::Idle, // unknown command: no response
+                }
+            }
+        };
+
+        let mut buf = vec![EXT_GETTER, cmd, payload.len() as u8];
+        buf.extend_from_slice(&payload);
+        let mut crc = self.crc8.calc(&buf, buf.len() as i32, 0);
+        if self.crc_errors > 0 {
+            self.crc_errors -= 1;
+            crc = !crc;
+        }
+        buf.push(crc);
+
+        self.output.push_back(0xff);
+        self.output.extend(&buf[2..]);
+        State::Idle
+    }
+}
+
+impl Transport for Emulator {
+    fn write(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        for c in buf {
+            self.exchange(*c);
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), io::Error> {
+        for c in buf.iter_mut() {
+            *c = self.exchange(0x00);
+        }
+        Ok(())
+    }
+
+    fn transfer(&mut self, tx: &[u8], rx: &mut [u8]) -> Result<(), io::Error> {
+        assert_eq!(tx.len(), rx.len());
+        for i in 0..tx.len() {
+            rx[i] = self.exchange(tx[i]);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_emulator_registers() {
+    let mut peripheral = Peripheral::new(Emulator::new());
+    peripheral.resync().unwrap();
+    assert_eq!(peripheral.read_number(CMD_TEST, 2).unwrap(), 0xabcd);
+    assert_eq!(peripheral.read_number(CMD_TEMP_AVG, 2).unwrap(), 2150 + 5500);
+    peripheral.write_number(CMD_COLOR, 4, 0x4148ffff).unwrap();
+    assert_eq!(peripheral.read_number(CMD_COLOR, 4).unwrap(), 0x4148ffff);
+}
+
+#[test]
+fn test_emulator_events() {
+    let mut peripheral = Peripheral::new(Emulator::new());
+    assert_eq!(peripheral.read_number(CMD_EVENTS, 2).unwrap(), 0);
+    peripheral.transport_mut().press_button(0x4148ffff);
+    assert_eq!(peripheral.read_number(CMD_EVENTS, 2).unwrap(), EVENT_COLOR);
+    assert_eq!(peripheral.read_number(CMD_EVENTS, 2).unwrap(), 0);
+    assert_eq!(peripheral.read_number(CMD_COLOR, 4).unwrap(), 0x4148ffff);
+}
+
+#[test]
+fn test_emulator_faults() {
+    let mut peripheral = Peripheral::new(Emulator::new());
+    peripheral.set_retry_policy(RetryPolicy {
+        retries: 0,
+        resync_after: 0,
+        ..Default::default()
+    });
+
+    peripheral.transport_mut().inject_crc_error();
+    assert!(peripheral.read_number(CMD_TEST, 2).is_err());
+    assert_eq!(peripheral.read_number(CMD_TEST, 2).unwrap(), 0xabcd);
+
+    peripheral.transport_mut().inject_desync(3);
+    assert!(peripheral.read_number(CMD_TEST, 2).is_err());
+    peripheral.resync().unwrap();
+    assert_eq!(peripheral.read_number(CMD_TEST, 2).unwrap(), 0xabcd);
+}
+
+#[test]
+fn test_emulator_configured_faults() {
+    let mut peripheral = Peripheral::new(Emulator::new());
+    peripheral.set_byte_delay(time::Duration::from_millis(0));
+    peripheral.transport_mut().set_faults(Faults {
+        crc_error_every: 5,
+        desync_every: 13,
+        button_interval: Some(time::Duration::from_millis(1)),
+        ..Default::default()
+    });
+    for _ in 0..20 {
+        assert_eq!(peripheral.read_number(CMD_TEST, 2).unwrap(), TEST_VALUE);
+    }
+    assert!(peripheral.stats().crc_errors > 0);
+    assert!(peripheral.stats().framing_errors > 0);
+    assert_eq!(peripheral.stats().failures, 0);
+
+    peripheral.transport_mut().set_faults(Faults {
+        button_interval: Some(time::Duration::from_millis(1)),
+        ..Default::default()
+    });
+    ::std::thread::sleep(time::Duration::from_millis(2));
+    assert_eq!(peripheral.read_number(CMD_EVENTS, 2).unwrap(), EVENT_COLOR);
+    assert!(peripheral.read_number(CMD_COLOR, 4).unwrap() != 0);
+}
+
+#[test]
+fn test_emulator_retry() {
+    let mut peripheral = Peripheral::new(Emulator::new());
+
+    peripheral.transport_mut().inject_crc_error();
+    assert_eq!(peripheral.read_number(CMD_TEST, 2).unwrap(), 0xabcd);
+    assert_eq!(peripheral.stats().crc_errors, 1);
+
+    peripheral.transport_mut().inject_desync(3);
+    assert_eq!(peripheral.read_number(CMD_TEST, 2).unwrap(), 0xabcd);
+    assert_eq!(peripheral.stats().resyncs, 1);
+    assert_eq!(peripheral.stats().failures, 0);
+}
+
+#[test]
+fn test_emulator_verify_writes() {
+    use error::DomoError;
+
+    let mut peripheral = Peripheral::new(Emulator::new());
+    peripheral.set_verify_writes(true);
+    peripheral.set_retry_policy(RetryPolicy {
+        retries: 0,
+        resync_after: 0,
+        ..Default::default()
+    });
+
+    peripheral.transport_mut().inject_dropped_write();
+    match peripheral.write_number(CMD_COLOR, 4, 0x4148ffff) {
+        Err(DomoError::WriteMismatch { read: 0, .. }) => {}
+        result => panic!("expected write mismatch, got {:?}", result),
+    }
+
+    peripheral.set_retry_policy(RetryPolicy::default());
+    peripheral.transport_mut().inject_dropped_write();
+    peripheral.write_number(CMD_COLOR, 4, 0x4148ffff).unwrap();
+    assert_eq!(peripheral.read_number(CMD_COLOR, 4).unwrap(), 0x4148ffff);
+}
+
+#[test]
+fn test_emulator_discover() {
+    let mut peripheral = Peripheral::new(Emulator::new());
+    let capabilities = peripheral.discover().unwrap();
+    assert_eq!(capabilities.version, PROTOCOL_VERSION);
+    assert_eq!(capabilities.registers.len(), Capabilities::legacy().registers.len() + 5);
+    assert!(capabilities.can_write(CMD_COLOR));
+    assert!(capabilities.can_write(CMD_BOOT_PAGE));
+    assert!(!capabilities.can_write(CMD_TEMP_AVG));
+}
+
+#[test]
+fn test_emulator_extended() {
+    let mut peripheral = Peripheral::new(Emulator::new());
+    assert_eq!(peripheral.read_string(CMD_FIRMWARE_VERSION).unwrap(),
+               "domo-avr emulator");
+    assert_eq!(peripheral.read_number(CMD_TEST, 4).unwrap(), 0xabcd);
+    assert_eq!(peripheral.read_u64(CMD_TEST).is_err(), true);
+
+    peripheral.write_bytes(CMD_FIRMWARE_VERSION, b"test").unwrap();
+    assert_eq!(peripheral.read_bytes(CMD_FIRMWARE_VERSION).unwrap(), b"test");
+    peripheral.write_u64(CMD_FIRMWARE_VERSION, 0x0123456789abcdef).unwrap();
+    assert_eq!(peripheral.read_u64(CMD_FIRMWARE_VERSION).unwrap(), 0x0123456789abcdef);
+
+    peripheral.write_number(CMD_COLOR, 3, 0x48ffff).unwrap();
+    assert_eq!(peripheral.read_number(CMD_COLOR, 4).unwrap(), 0x48ffff);
+}
+
+#[test]
+fn test_emulator_batch() {
+    let mut peripheral = Peripheral::new(Emulator::new());
+    peripheral.set_batch_transfers(true);
+    let results = peripheral.run_batch(&[BatchOp::Set {
+                                             cmd: CMD_COLOR,
+                                             length: 4,
+                                             value: 0x4148ffff,
+                                         },
+                                         BatchOp::Get {
+                                             cmd: CMD_COLOR,
+                                             length: 4,
+                                         },
+                                         BatchOp::Get {
+                                             cmd: CMD_TEST,
+                                             length: 2,
+                                         }])
+        .unwrap();
+    let results: Vec<u32> = results.into_iter().map(|r| r.unwrap()).collect();
+    assert_eq!(results, vec![0x4148ffff, 0x4148ffff, 0xabcd]);
+
+    // A failed access is retried on its own.
+    peripheral.transport_mut().inject_crc_error();
+    let results = peripheral.run_batch(&[BatchOp::Get {
+                                             cmd: CMD_TEST,
+                                             length: 2,
+                                         }])
+        .unwrap();
+    assert_eq!(results[0].as_ref().unwrap(), &0xabcd);
+    assert_eq!(peripheral.stats().crc_errors, 1);
+    assert_eq!(peripheral.stats().failures, 0);
+
+    // With a byte delay the accesses are done one by one.
+    peripheral.set_batch_transfers(false);
+    let results = peripheral.run_batch(&[BatchOp::Set {
+                                             cmd: CMD_COLOR,
+                                             length: 3,
+                                             value: 0x48ffff,
+                                         }])
+        .unwrap();
+    assert_eq!(results[0].as_ref().unwrap(), &0x48ffff);
+    assert_eq!(peripheral.read_number(CMD_COLOR, 4).unwrap(), 0x48ffff);
+}
+
+#[test]
+fn test_emulator_flash() {
+    use std::collections::BTreeMap;
+    use flash;
+
+    let mut image = BTreeMap::new();
+    for address in 0..300 {
+        image.insert(address, address as u8);
+    }
+    image.insert(0x1000, 0x42);
+
+    let mut peripheral = Peripheral::new(Emulator::new());
+    flash::flash(&mut peripheral, &image, &mut Vec::new()).unwrap();
+    let emulator = peripheral.transport_mut();
+    for address in 0..300 {
+        assert_eq!(emulator.flash[address], address as u8);
+    }
+    assert_eq!(emulator.flash[300], 0xff);
+    assert_eq!(emulator.flash[0x1000], 0x42);
+    assert_eq!(emulator.registers[&CMD_BOOT_CONTROL], flash::BOOT_LEAVE);
+
+    // Jumping to the bootloader isn't mistaken for a failed write.
+    let mut peripheral = Peripheral::new(Emulator::new());
+    peripheral.set_verify_writes(true);
+    flash::flash(&mut peripheral, &image, &mut Vec::new()).unwrap();
+    assert_eq!(peripheral.transport_mut().flash[0x1000], 0x42);
+    assert_eq!(peripheral.stats().errors(), 0);
+
+    // An image beyond application flash never enters the bootloader.
+    let mut peripheral = Peripheral::new(Emulator::new());
+    image.insert(0x10008, 0xab);
+    assert!(flash::flash(&mut peripheral, &image, &mut Vec::new()).is_err());
+    assert!(peripheral.transport_mut().registers.get(&CMD_BOOT_CONTROL) != Some(&BOOT_ENTER));
+}
@@ -0,0 +1,108 @@
+use std::{error, fmt, io};
+
+
+// All errors that can happen in domoc. The variants tell apart a broken link
+// to the peripheral, a misbehaving peripheral, bad configuration and a
+// misbehaving server.
+#[derive(Debug)]
+pub enum DomoError {
+    // The bus to the peripheral failed, e.g. the device is unplugged.
+    Transport(io::Error),
+    // The peripheral sent a different byte than the protocol expects.
+    Framing { expected: u8, got: u8 },
+    // A frame was received with a wrong checksum.
+    Crc { received: u8, calculated: u8 },
+    // Reading back a register after writing it returned a different value.
+    WriteMismatch { cmd: u8, written: u32, read: u32 },
+    // The configuration is missing or invalid.
+    Config(String),
+    // The peripheral doesn't speak the protocol as expected.
+    Protocol(String),
+    // The server sent a message that can't be handled.
+    ServerMessage(String),
+    // A request was made that can't be expressed in the protocol.
+    Argument(String),
+}
+
+impl DomoError {
+    // Whether this error means the peripheral is probably out of sync.
+    pub fn is_bad_frame(&self) -> bool {
+        match *self {
+            DomoError::Framing { .. } |
+            DomoError::Crc { .. } |
+            DomoError::WriteMismatch { .. } => true,
+            _ => false,
+        }
+    }
+
+    // Process exit status for this error, so supervisors can tell the
+    // different kinds of failures apart.
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            DomoError::Config(_) => 2,
+            DomoError::Transport(_) => 3,
+            DomoError::Framing { .. } |
+            DomoError::Crc { .. } |
+            DomoError::WriteMismatch { .. } |
+            DomoError::Protocol(_) => 4,
+            DomoError::ServerMessage(_) => 5,
+            DomoError::Argument(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for DomoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DomoError::Transport(ref err) => write!(f, "transport error: {}", err),
+            DomoError::Framing { expected, got } => {
+                write!(f, "expected {:02x} from peripheral, got {:02x}", expected, got)
+            }
+            DomoError::Crc { received, calculated } => {
+                write!(f,
+                       "CRC check failed (received {:02x}, calculated {:02x})",
+                       received,
+                       calculated)
+            }
+            DomoError::WriteMismatch { cmd, written, read } => {
+                write!(f,
+                       "register {:02x} reads {:08x} after writing {:08x}",
+                       cmd,
+                       read,
+                       written)
+            }
+            DomoError::Config(ref msg) => write!(f, "config error: {}", msg),
+            DomoError::Protocol(ref msg) => write!(f, "protocol error: {}", msg),
+            DomoError::ServerMessage(ref msg) => write!(f, "bad server message: {}", msg),
+            DomoError::Argument(ref msg) => write!(f, "invalid argument: {}", msg),
+        }
+    }
+}
+
+impl error::Error for DomoError {
+    fn description(&self) -> &str {
+        match *self {
+            DomoError::Transport(ref err) => err.description(),
+            DomoError::Framing { .. } => "unexpected byte from peripheral",
+            DomoError::Crc { .. } => "CRC check failed",
+            DomoError::WriteMismatch { .. } => "register value differs from written value",
+            DomoError::Config(ref msg) => msg,
+            DomoError::Protocol(ref msg) => msg,
+            DomoError::ServerMessage(ref msg) => msg,
+            DomoError::Argument(ref msg) => msg,
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            DomoError::Transport(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DomoError {
+    fn from(err: io::Error) -> DomoError {
+        DomoError::Transport(err)
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::{thread, time};
+
+use error::DomoError;
+use peripheral::*;
+use transport::Transport;
+
+
+// Size of a flash page of the ATmega328P. The bootloader writes whole pages.
+pub const PAGE_SIZE: usize = 128;
+
+// Application flash of the ATmega328P, the upper 4kB holds the bootloader.
+pub const APP_FLASH_SIZE: usize = 28 * 1024;
+
+// Values for CMD_BOOT_CONTROL.
+pub const BOOT_ENTER: u32 = 1; // jump to the bootloader
+pub const BOOT_LEAVE: u32 = 2; // start the application
+
+// Time the AVR needs to jump to the bootloader or the application.
+const BOOT_DELAY: u64 = 100; // 100ms
+
+// Number of times a page is written again when it reads back differently.
+const PAGE_RETRIES: u32 = 3;
+
+// Split a firmware image into flash pages. Bytes not in the image are filled
+// with 0xff, like erased flash.
+pub fn pages(image: &BTreeMap<u32, u8>) -> BTreeMap<u32, Vec<u8>> {
+    let mut pages = BTreeMap::new();
+    for (address, c) in image {
+        let page_address = address - address % PAGE_SIZE as u32;
+        let page = pages.entry(page_address).or_insert_with(|| vec![0xff; PAGE_SIZE]);
+        page[(address - page_address) as usize] = *c;
+    }
+    pages
+}
+
+// Program a firmware image into the AVR using the bootloader of domo-avr,
+// which speaks the same framed protocol as the firmware itself. Every page is
+// read back after writing it. Afterwards the new firmware is started and the
+// link is resynced. An image that doesn't fit in application flash is
+// rejected before entering the bootloader.
+pub fn flash<T: Transport>(peripheral: &mut Peripheral<T>,
+                           image: &BTreeMap<u32, u8>,
+                           out: &mut Write)
+                           -> Result<(), DomoError> {
+    if let Some(address) = image.keys().next_back() {
+        if *address as usize >= APP_FLASH_SIZE {
+            return Err(DomoError::Argument(format!("firmware doesn't fit in flash: address \
+                                                    {:04x} is beyond {:04x}",
+                                                   address,
+                                                   APP_FLASH_SIZE)));
+        }
+    }
+    let pages = pages(image);
+
+    try!(peripheral.write_number_unverified(CMD_BOOT_CONTROL, 2, BOOT_ENTER));
+    thread::sleep(time::Duration::from_millis(BOOT_DELAY));
+    try!(peripheral.resync());
+
+    for (i, (address, page)) in pages.iter().enumerate() {
+        try!(write!(out, "\rwriting page {}/{}", i + 1, pages.len()));
+        try!(out.flush());
+        try!(write_page(peripheral, *address, page));
+    }
+    try!(writeln!(out, ""));
+
+    try!(peripheral.write_number_unverified(CMD_BOOT_CONTROL, 2, BOOT_LEAVE));
+    thread::sleep(time::Duration::from_millis(BOOT_DELAY));
+    peripheral.resync()
+}
+
+fn write_page<T: Transport>(peripheral: &mut Peripheral<T>,
+                            address: u32,
+                            page: &[u8])
+                            -> Result<(), DomoError> {
+    let mut attempt = 0;
+    loop {
+        try!(peripheral.write_number(CMD_BOOT_ADDRESS, 4, address));
+        try!(peripheral.write_bytes(CMD_BOOT_PAGE, page));
+        try!(peripheral.write_number(CMD_BOOT_ADDRESS, 4, address));
+        let read = try!(peripheral.read_bytes(CMD_BOOT_PAGE));
+        if &read[..] == page {
+            return Ok(());
+        }
+        if attempt >= PAGE_RETRIES {
+            return Err(DomoError::Protocol(format!("flash page at {:04x} doesn't verify",
+                                                   address)));
+        }
+        attempt += 1;
+    }
+}
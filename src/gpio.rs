@@ -0,0 +1,74 @@
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time;
+
+use libc;
+
+
+const SYSFS_GPIO: &'static str = "/sys/class/gpio";
+
+// Interrupt ("data ready") line from the peripheral, connected to a GPIO pin of
+// the Raspberry Pi. The AVR pulls the line low when it has pending events.
+pub struct IrqLine {
+    value: File,
+}
+
+impl IrqLine {
+    // Export the GPIO through sysfs and configure it as an input that
+    // interrupts on the falling edge.
+    pub fn open(gpio: u32) -> Result<IrqLine, io::Error> {
+        let path = format!("{}/gpio{}", SYSFS_GPIO, gpio);
+        if !Path::new(&path).exists() {
+            try!(write_file(&format!("{}/export", SYSFS_GPIO), &gpio.to_string()));
+        }
+        try!(write_file(&format!("{}/direction", path), "in"));
+        try!(write_file(&format!("{}/edge", path), "falling"));
+
+        let mut line = IrqLine { value: try!(File::open(format!("{}/value", path))) };
+        // Reading the value clears a pending interrupt.
+        try!(line.is_active());
+        Ok(line)
+    }
+
+    // Whether the line is currently pulled low.
+    pub fn is_active(&mut self) -> Result<bool, io::Error> {
+        let mut buf = [0; 1];
+        try!(self.value.seek(io::SeekFrom::Start(0)));
+        try!(self.value.read_exact(&mut buf));
+        Ok(buf[0] == b'0')
+    }
+
+    // Wait until the line becomes active, or the timeout expires. Returns
+    // whether the line is active.
+    pub fn wait(&mut self, timeout: time::Duration) -> Result<bool, io::Error> {
+        if try!(self.is_active()) {
+            return Ok(true);
+        }
+        self.wait_edge(timeout)
+    }
+
+    // Wait for the line to be pulled low again, even when it is active
+    // already, or until the timeout expires. Returns whether the line is
+    // active.
+    pub fn wait_edge(&mut self, timeout: time::Duration) -> Result<bool, io::Error> {
+        let mut pollfd = libc::pollfd {
+            fd: self.value.as_raw_fd(),
+            events: libc::POLLPRI | libc::POLLERR,
+            revents: 0,
+        };
+        let timeout_ms = timeout.as_secs() * 1000 + (timeout.subsec_nanos() / 1000000) as u64;
+        let result = unsafe { libc::poll(&mut pollfd, 1, timeout_ms as libc::c_int) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.is_active()
+    }
+}
+
+fn write_file(path: &str, value: &str) -> Result<(), io::Error> {
+    let mut file = try!(fs::OpenOptions::new().write(true).open(path));
+    file.write_all(value.as_bytes())
+}
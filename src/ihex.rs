@@ -0,0 +1,100 @@
+use std::collections::BTreeMap;
+
+
+// Record types in an Intel HEX file.
+const RECORD_DATA: u8 = 0x00;
+const RECORD_EOF: u8 = 0x01;
+const RECORD_EXTENDED_SEGMENT: u8 = 0x02;
+const RECORD_START_SEGMENT: u8 = 0x03;
+const RECORD_EXTENDED_LINEAR: u8 = 0x04;
+const RECORD_START_LINEAR: u8 = 0x05;
+
+// Parse an Intel HEX file, as produced by avr-objcopy, into a map from
+// address to byte. Start address records are ignored, the AVR always starts at
+// address 0.
+pub fn parse(text: &str) -> Result<BTreeMap<u32, u8>, String> {
+    let mut data = BTreeMap::new();
+    let mut base: u32 = 0;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let record = try!(parse_record(line).map_err(|msg| format!("line {}: {}", i + 1, msg)));
+
+        let address = (record[1] as u32) << 8 | record[2] as u32;
+        let record_type = record[3];
+        let payload = &record[4..record.len() - 1];
+        match record_type {
+            RECORD_DATA => {
+                for (offset, c) in payload.iter().enumerate() {
+                    match base.checked_add(address + offset as u32) {
+                        Some(address) => data.insert(address, *c),
+                        None => return Err(format!("line {}: address out of range", i + 1)),
+                    };
+                }
+            }
+            RECORD_EOF => return Ok(data),
+            RECORD_EXTENDED_SEGMENT | RECORD_EXTENDED_LINEAR if payload.len() == 2 => {
+                let value = (payload[0] as u32) << 8 | payload[1] as u32;
+                base = if record_type == RECORD_EXTENDED_SEGMENT {
+                    value << 4
+                } else {
+                    value << 16
+                };
+            }
+            RECORD_START_SEGMENT | RECORD_START_LINEAR => {}
+            _ => return Err(format!("line {}: invalid record type {:02x}", i + 1, record_type)),
+        }
+    }
+    Err("missing end of file record".to_string())
+}
+
+// Decode the bytes of a record (after the colon) and check the length and
+// checksum.
+fn parse_record(line: &str) -> Result<Vec<u8>, String> {
+    if !line.starts_with(':') {
+        return Err("record doesn't start with ':'".to_string());
+    }
+    if line.bytes().any(|c| c >= 0x80) {
+        return Err("invalid character in record".to_string());
+    }
+    let hex = &line[1..];
+    if hex.len() % 2 != 0 || hex.len() < 10 {
+        return Err("invalid record length".to_string());
+    }
+    let mut record = Vec::new();
+    for i in 0..hex.len() / 2 {
+        match u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16) {
+            Ok(c) => record.push(c),
+            Err(_) => return Err(format!("invalid hex digits: {}", &hex[i * 2..i * 2 + 2])),
+        }
+    }
+    if record.len() != record[0] as usize + 5 {
+        return Err("record length doesn't match byte count".to_string());
+    }
+    let sum = record.iter().fold(0u8, |sum, c| sum.wrapping_add(*c));
+    if sum != 0 {
+        return Err("checksum mismatch".to_string());
+    }
+    Ok(record)
+}
+
+#[test]
+fn test_ihex_parse() {
+    let data = parse(":100000000C9434000C943E000C943E000C943E0082\n\
+                      :020000040001F9\n\
+                      :02000800ABCD7E\n\
+                      :00000001FF\n")
+        .unwrap();
+    assert_eq!(data.len(), 18);
+    assert_eq!(data[&0], 0x0c);
+    assert_eq!(data[&15], 0x00);
+    assert_eq!(data[&0x10008], 0xab);
+    assert_eq!(data[&0x10009], 0xcd);
+
+    assert!(parse(":100000000C9434000C943E000C943E000C943E0083\n:00000001FF\n").is_err());
+    assert!(parse(":02000800ABCD7E\n").is_err());
+    assert!(parse(":0200080é0ABCD7E\n").is_err());
+    assert!(parse(":02000004FFFFFC\n:02FFFF00ABCD88\n:00000001FF\n").is_err());
+}
@@ -0,0 +1,91 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::{thread, time};
+
+use libc;
+
+
+// Time between attempts to take a lock held by another process.
+const LOCK_POLL_INTERVAL: u64 = 5; // 5ms
+
+// Advisory lock (flock) on the bus device or a lock file, shared by all domoc
+// processes. The lock can be taken recursively: it is only released when
+// every acquire() is matched by a release().
+pub struct BusLock {
+    file: File,
+    timeout: time::Duration,
+    depth: u32,
+}
+
+impl BusLock {
+    // The lock only needs read access, so the SPI device or an existing lock
+    // file is opened read-only. A lock file that doesn't exist yet is created.
+    pub fn open(path: &str, timeout: time::Duration) -> Result<BusLock, io::Error> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                try!(OpenOptions::new().write(true).create(true).open(path))
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(BusLock {
+            file: file,
+            timeout: timeout,
+            depth: 0,
+        })
+    }
+
+    // Take the lock, waiting at most the timeout for other processes to
+    // release it.
+    pub fn acquire(&mut self) -> Result<(), io::Error> {
+        if self.depth == 0 {
+            let start = time::Instant::now();
+            loop {
+                let fd = self.file.as_raw_fd();
+                if unsafe { libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+                    break;
+                }
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::WouldBlock {
+                    return Err(err);
+                }
+                if start.elapsed() >= self.timeout {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                              "bus is locked by another process"));
+                }
+                thread::sleep(time::Duration::from_millis(LOCK_POLL_INTERVAL));
+            }
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    pub fn release(&mut self) {
+        self.depth -= 1;
+        if self.depth == 0 {
+            unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+        }
+    }
+}
+
+#[test]
+fn test_bus_lock() {
+    use std::{env, fs};
+
+    let path = env::temp_dir().join("domoc-test-lock");
+    let path = path.to_str().unwrap();
+    let _ = fs::remove_file(path);
+
+    let timeout = time::Duration::from_millis(20);
+    let mut lock1 = BusLock::open(path, timeout).unwrap();
+    let mut lock2 = BusLock::open(path, timeout).unwrap();
+
+    lock1.acquire().unwrap();
+    lock1.acquire().unwrap();
+    lock1.release();
+    assert_eq!(lock2.acquire().unwrap_err().kind(), io::ErrorKind::TimedOut);
+    lock1.release();
+    lock2.acquire().unwrap();
+    lock2.release();
+}
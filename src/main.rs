@@ -1,71 +1,216 @@
 
-use std::{env, fs, io, process, thread, time};
+use std::{env, fs, iter, process, thread, time};
+use std::ffi::OsString;
+use std::io;
+use std::io::prelude::*;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
 use std::sync::mpsc::{channel, Sender, Receiver};
 
 extern crate chrono;
+extern crate clap;
 extern crate crc8;
 extern crate env_logger;
+extern crate libc;
+extern crate rustyline;
+extern crate serial;
+extern crate serde;
 extern crate serde_json;
 extern crate spidev;
 extern crate ws;
 
+mod bench;
+mod board;
+mod capabilities;
+mod control;
+mod diagnose;
+mod emulator;
+mod error;
+mod flash;
+mod gpio;
+mod ihex;
+mod lock;
 mod peripheral;
+mod trace;
+mod transport;
+mod uart;
 mod messages;
 mod socket;
+mod thermistor;
 
+use board::{Board, Temperature, decode_temp};
+use gpio::IrqLine;
 use peripheral::*;
+use error::DomoError;
 use messages::*;
 use chrono::*;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use rustyline::error::ReadlineError;
+use serde::Serialize;
 
 
 const LOG_INTERVAL: i64 = 60 * 5; // 5 minutes
 const SERVER_URL: &'static str = "wss://domo.aykevl.nl/api/ws/device";
-const CONFIG_PATH: &'static str = ".config/domo.json";
+const CONFIG_PATH: &'static str = ".config/domo.json"; // relative to the home directory
+const HISTORY_PATH: &'static str = ".domoc_history"; // relative to the home directory
 const SPIDEV_PATH: &'static str = "/dev/spidev0.0";
 const COLOR_READ_TIMEOUT: u64 = 5; // 5 seconds
+const IRQ_TIMEOUT: u64 = 60; // 1 minute
+const DIAGNOSE_FAILED: i32 = 4; // exit status when a check fails, like a protocol error
 
+// Help of register id arguments.
+const REGISTER_HELP: &'static str = "Register number, in decimal or hex (0x12), up to 0x3e (0x3f \
+                                      starts an extended frame, which would desync the link)";
 
-fn decode_temp(value: u32) -> f64 {
-    // Value holds temperature in centidegrees, where 0 equals -55°C.
-    // Convert this value to regular °C readings.
-    ((value as i32 - 5500) as f64) / 100.0
+// Values the watch command can read.
+const WATCH_VALUES: &'static [&'static str] = &["temp", "temp-avg", "temp-now", "temp-raw",
+                                                 "temp-rsum", "color", "reg"];
+
+// Set by SIGINT while watching, to stop cleanly.
+static INTERRUPTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+
+// Parse a number given on the command line, in decimal or in hex with a 0x
+// prefix.
+fn parse_number(text: &str) -> Result<u32, DomoError> {
+    let result = if text.starts_with("0x") || text.starts_with("0X") {
+        u32::from_str_radix(&text[2..], 16)
+    } else {
+        text.parse()
+    };
+    result.map_err(|err| DomoError::Argument(format!("could not parse \"{}\": {}", text, err)))
+}
+
+// Like parse_number, for values of registers up to 8 bytes.
+fn parse_u64(text: &str) -> Result<u64, DomoError> {
+    let result = if text.starts_with("0x") || text.starts_with("0X") {
+        u64::from_str_radix(&text[2..], 16)
+    } else {
+        text.parse()
+    };
+    result.map_err(|err| DomoError::Argument(format!("could not parse \"{}\": {}", text, err)))
+}
+
+// Register id and width (in bytes) given on the command line.
+fn parse_register(args: &ArgMatches) -> Result<(u8, u8), DomoError> {
+    let id = match args.value_of("id") {
+        Some(id) => try!(parse_number(id)),
+        None => return Err(DomoError::Argument("missing register id".to_string())),
+    };
+    if id > (!TYPE_MASK) as u32 {
+        return Err(DomoError::Argument(format!("register {:#x} is out of range", id)));
+    }
+    // Accessing it would start an extended frame, which desyncs the link.
+    if id == CMD_EXTENDED as u32 {
+        return Err(DomoError::Argument(format!("register {:#x} is reserved for extended frames",
+                                               id)));
+    }
+    Ok((id as u8, args.value_of("width").unwrap().parse().unwrap()))
+}
+
+// Parse a comma separated list of numbers. No list gives an empty list.
+fn parse_list(text: Option<&str>) -> Result<Vec<u32>, DomoError> {
+    match text {
+        Some(text) => text.split(',').map(|item| parse_number(item.trim())).collect(),
+        None => Ok(Vec::new()),
+    }
+}
+
+// Parse a color given as a raw value in hex, or as "hsv" or "rgb" followed by
+// three values between 0 and 1.
+fn parse_color(values: &[&str]) -> Result<u32, DomoError> {
+    if values.len() == 1 {
+        return u32::from_str_radix(values[0], 16).map_err(|err| {
+            DomoError::Argument(format!("could not parse \"{}\": {}", values[0], err))
+        });
+    }
+    if values.len() != 4 {
+        return Err(DomoError::Argument("expected a raw color, or hsv or rgb with three values"
+            .to_string()));
+    }
+    let mut components = [0.0; 3];
+    for (component, text) in components.iter_mut().zip(&values[1..]) {
+        *component = match text.parse() {
+            Ok(val) if val >= 0.0 && val <= 1.0 => val,
+            _ => {
+                return Err(DomoError::Argument(format!("\"{}\" is not a number between 0 and 1",
+                                                       text)))
+            }
+        };
+    }
+    let color = match values[0] {
+        "hsv" => Color::hsv(components[0], components[1], components[2]),
+        "rgb" => Color::rgb(components[0], components[1], components[2]),
+        mode => return Err(DomoError::Argument(format!("unknown color mode \"{}\"", mode))),
+    };
+    Ok(color.raw())
+}
+
+// Load configuration (name, serial number) to identify this controller to the server.
+fn load_config(path: Option<&str>) -> Result<Config, DomoError> {
+    let path = match path {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let mut path = match env::home_dir() {
+                Some(path) => path,
+                None => return Err(DomoError::Config("could not find home directory".to_string())),
+            };
+            path.push(CONFIG_PATH);
+            path
+        }
+    };
+    let mut text = String::new();
+    if let Err(err) = fs::File::open(&path).and_then(|mut f| f.read_to_string(&mut text)) {
+        return Err(DomoError::Config(format!("could not open {}: {}", path.display(), err)));
+    }
+    match Config::parse(&text) {
+        Ok(config) => Ok(config),
+        Err(err) => Err(DomoError::Config(format!("could not parse {}: {}", path.display(), err))),
+    }
 }
 
 struct Domo {
     config: Config,
-    peripheral: Peripheral,
-    color: Color,
-    temp_b_coefficient: Option<f64>,
-    temp_nominal_r: Option<f64>,
-    temp_series_resistor: Option<f64>,
+    boards: Vec<Board>,
+    // Index of the board used by one-shot commands.
+    selected: usize,
+    // State of the daemon, when running as one.
+    started: Option<DateTime<Local>>,
+    connected: Arc<Mutex<bool>>,
 }
 
 impl Domo {
-    fn new(spidev_path: &str) -> Result<Self, io::Error> {
-        let peripheral = match Peripheral::open(spidev_path) {
-            Ok(peripheral) => peripheral,
-            Err(err) => {
-                println!("Could not open SPI device: {}", err);
-                process::exit(1);
+    fn new(config: Config, spidev_path: &str) -> Result<Self, DomoError> {
+        let mut boards: Vec<Board> = Vec::new();
+        for peripheral_config in config.peripherals() {
+            if boards.iter().any(|b| b.name == peripheral_config.name) {
+                return Err(DomoError::Config(format!("duplicate peripheral name: {}",
+                                                     peripheral_config.name)));
             }
-        };
-
-        // Load configuration (name, serial number) to identify this controller to the server.
-        // TODO error handling
-        let mut path = env::home_dir().expect("could not find home directory");
-        path.push(CONFIG_PATH);
-        let f: fs::File = fs::File::open(path).expect("could not open config file");
-        let config = serde_json::from_reader(f).expect("could not parse config file");
+            boards.push(try!(Board::open(peripheral_config, spidev_path)));
+        }
+        if boards.is_empty() {
+            return Err(DomoError::Config("no peripherals configured".to_string()));
+        }
+        Ok(Domo::with_boards(config, boards))
+    }
 
-        Ok(Domo {
+    fn with_boards(config: Config, boards: Vec<Board>) -> Self {
+        Domo {
             config: config,
-            peripheral: peripheral,
-            color: Color::new(),
-            temp_b_coefficient: None,
-            temp_nominal_r: None,
-            temp_series_resistor: None,
-        })
+            boards: boards,
+            selected: 0,
+            started: None,
+            connected: Arc::new(Mutex::new(false)),
+        }
+    }
+
+    // Domo with emulated boards with these names, for tests.
+    #[cfg(test)]
+    fn emulated(names: &[&str]) -> Self {
+        let config = Config::parse(r#"{"name": "", "serial": "", "peripherals": []}"#).unwrap();
+        Domo::with_boards(config, names.iter().map(|name| Board::emulated(name, "")).collect())
     }
 
     fn get_name(&self) -> String {
@@ -76,155 +221,195 @@ impl Domo {
         return self.config.serial.clone();
     }
 
-    fn resync(&mut self) -> Result<(), io::Error> {
-        self.peripheral.resync()
+    // Board used by one-shot commands.
+    fn board(&mut self) -> &mut Board {
+        &mut self.boards[self.selected]
     }
 
-    fn read_number(&mut self, cmd: u8, length: u8) -> Result<u32, io::Error> {
-        self.peripheral.read_number(cmd, length)
+    // Select the board used by one-shot commands by its name.
+    fn select(&mut self, name: &str) -> Result<(), DomoError> {
+        match self.boards.iter().position(|b| b.name == name) {
+            Some(index) => {
+                self.selected = index;
+                Ok(())
+            }
+            None => Err(DomoError::Argument(format!("unknown peripheral: {}", name))),
+        }
     }
 
-    fn write_number(&mut self, cmd: u8, length: u8, value: u32) -> Result<(), io::Error> {
-        self.peripheral.write_number(cmd, length, value)
+    // Find the board for an actuator name as sent by the server (see
+    // Board::sensor_name), and return it with the unprefixed actuator name.
+    fn find_actuator<'a, 'b>(&'a mut self, name: &'b str) -> Option<(&'a mut Board, &'b str)> {
+        let (board_name, actuator) = match name.rfind('/') {
+            Some(i) => (&name[..i], &name[i + 1..]),
+            None => ("", name),
+        };
+        self.boards.iter_mut().find(|b| b.name == board_name).map(|b| (b, actuator))
     }
 
-    fn read_temp_raw(&mut self) -> Result<f64, io::Error> {
-        let raw_value = try!(self.peripheral.read_number(CMD_TEMP_RAW, 4));
-        self.raw_to_celsius(raw_value, 10)
+    fn resync(&mut self) -> Result<(), DomoError> {
+        self.board().peripheral.resync()
     }
 
-    fn read_temp_rsum(&mut self) -> Result<f64, io::Error> {
-        let raw_value = try!(self.peripheral.read_number(CMD_TEMP_RSUM, 4));
-        self.raw_to_celsius(raw_value, 18)
+    fn read_number(&mut self, cmd: u8, length: u8) -> Result<u32, DomoError> {
+        self.board().peripheral.read_number(cmd, length)
     }
 
-    fn get_temp_b_coefficient(&mut self) -> Result<f64, io::Error> {
-        Ok(match self.config.temp_b_coefficient {
-            Some(val) => val,
-            None => match self.temp_b_coefficient {
-                Some(val) => val,
-                None => {
-                    let b_coefficient = try!(self.peripheral.read_number(CMD_TEMP_BCOE, 2)) as f64;
-                    self.temp_b_coefficient = Some(b_coefficient);
-                    b_coefficient // return
-                }
-            }
-        })
+    fn write_number(&mut self, cmd: u8, length: u8, value: u32) -> Result<(), DomoError> {
+        self.board().peripheral.write_number(cmd, length, value)
     }
 
-    fn get_temp_nominal_r(&mut self) -> Result<f64, io::Error> {
-        Ok(match self.config.temp_nominal_r {
-            Some(val) => val,
-            None => match self.temp_nominal_r {
-                Some(val) => val,
-                None => {
-                    let nominal_r = try!(self.peripheral.read_number(CMD_TEMP_NRES, 2)) as f64;
-                    self.temp_nominal_r = Some(nominal_r);
-                    nominal_r // return
-                }
-            }
-        })
+    fn read_temp(&mut self, cmd: u8) -> Result<Temperature, DomoError> {
+        self.board().read_temp(cmd)
     }
 
-    fn get_temp_series_resistor(&mut self) -> Result<f64, io::Error> {
-        Ok(match self.config.temp_nominal_r {
-            Some(val) => val,
-            None => match self.temp_series_resistor {
-                Some(val) => val,
-                None => {
-                    let series_resistor = try!(self.peripheral.read_number(CMD_TEMP_SRES, 2)) as f64;
-                    self.temp_nominal_r = Some(series_resistor);
-                    series_resistor // return
-                }
-            }
-        })
+    fn read_temp_raw(&mut self) -> Result<Temperature, DomoError> {
+        self.board().read_temp_raw()
     }
 
-    fn raw_to_celsius(&mut self, value: u32, bits: u32) -> Result<f64, io::Error> {
-        // Source: https://learn.adafruit.com/thermistor/using-a-thermistor
-        // TODO: these constants should be read from the microcontroller
-        let b_coefficient = try!(self.get_temp_b_coefficient());
-        let t0: f64 = 298.15;  // nominal temperature (25°C)
-        let r0: f64 = try!(self.get_temp_nominal_r()); // 10kΩ at 25°C
-        let series_resistor: f64 = try!(self.get_temp_series_resistor()); // 10kΩ series resistor
-
-        // convert value to range 0..1, where 0.5 means t=t0
-        let fvalue: f64 = value as f64 / (1 << bits) as f64;
-
-        // calculate resistance for NTC
-        let r = series_resistor / (1.0 / fvalue - 1.0);
-
-        // Steinhart-Hart equation
-        let tinv = (1.0 / t0) + 1.0 / b_coefficient * (r / r0).ln();
-        let t = 1.0 / tinv;
-
-        // convert from K to °C and return
-        Ok(t - 273.15)
+    fn read_temp_rsum(&mut self) -> Result<Temperature, DomoError> {
+        self.board().read_temp_rsum()
     }
 }
 
 fn log(domo: Arc<Mutex<Domo>>, tx_msg_to_server: Option<Arc<Mutex<Sender<String>>>>) {
     let now = Local::now();
-    let temp = match domo.lock().unwrap().peripheral.read_number(CMD_TEMP_AVG, 2) {
-        Ok(result) => Some(decode_temp(result)),
-        Err(err) => {
-            println!("failed to read temperature: {}", err);
-            None
-        }
-    };
-    match temp {
-        Some(temp) => println!("{:02}:{:02} {:.2}°C", now.hour(), now.minute(), temp),
-        None => {
-            println!("{:02}:{:02} <none>", now.hour(), now.minute());
-            return;
+    let mut temps = Vec::new();
+    {
+        let mut domo = domo.lock().unwrap();
+        for board in domo.boards.iter_mut() {
+            if !board.capabilities.can_read(CMD_TEMP_AVG) {
+                continue;
+            }
+            let name = board.sensor_name("temp");
+            match board.poll(CMD_TEMP_AVG, 2) {
+                Ok(result) => {
+                    let temp = board.correct_temp(decode_temp(result));
+                    if board.name.is_empty() {
+                        println!("{:02}:{:02} {}", now.hour(), now.minute(), temp);
+                    } else {
+                        println!("{:02}:{:02} {} ({})",
+                                 now.hour(),
+                                 now.minute(),
+                                 temp,
+                                 board.name);
+                    }
+                    board.last_temp = Some((temp.celsius, now));
+                    temps.push((name.clone(), temp.celsius));
+                }
+                Err(err) => {
+                    println!("failed to read temperature: {}", err);
+                    println!("{:02}:{:02} <none>", now.hour(), now.minute());
+                }
+            };
+
+            // Print link statistics when something went wrong since the last log.
+            let errors = board.peripheral.stats().errors();
+            if errors != board.reported_errors {
+                board.reported_errors = errors;
+                println!("peripheral {}: {}", name, board.peripheral.stats());
+            }
         }
-    };
+    }
 
     // Send temperature when tx_sensor is not None.
     match tx_msg_to_server {
         Some(tx_msg_to_server) => {
-            let msg = serde_json::to_string(&MsgSensorLog {
-                    message: "sensorLog".to_string(),
-                    name: "temp".to_string(),
-                    value: temp.unwrap(),
-                    time: now.timestamp(),
-                    sensor_type: "temperature".to_string(),
-                    interval: LOG_INTERVAL,
-                })
-                .unwrap();
-            tx_msg_to_server.lock().unwrap().send(msg).unwrap();
+            for (name, temp) in temps {
+                let msg = serde_json::to_string(&MsgSensorLog {
+                        message: "sensorLog".to_string(),
+                        name: name,
+                        value: temp,
+                        time: now.timestamp(),
+                        sensor_type: "temperature".to_string(),
+                        interval: LOG_INTERVAL,
+                    })
+                    .unwrap();
+                tx_msg_to_server.lock().unwrap().send(msg).unwrap();
+            }
         }
         None => {}
     }
 }
 
-fn actuator_to_server(domo: Arc<Mutex<Domo>>, tx_msg_to_server: Arc<Mutex<Sender<String>>>) {
+// Send the color to the server when it was changed on the board.
+fn report_color(board: &mut Board, tx_msg_to_server: &Arc<Mutex<Sender<String>>>) {
+    let color_raw = match board.poll(CMD_COLOR, 4) {
+        Ok(val) => val,
+        Err(err) => {
+            println!("could not read color: {}", err);
+            return;
+        }
+    };
+
+    if board.color.raw() == color_raw {
+        return;
+    }
+    board.color.update(color_raw);
+
+    println!("color change from peripheral: {:?}", board.color);
+    let msg = serde_json::to_string(&MsgColor {
+            message: "actuator".to_string(),
+            name: board.sensor_name("color"),
+            value: board.color.clone(),
+        })
+        .unwrap();
+    tx_msg_to_server.lock().unwrap().send(msg).unwrap();
+}
+
+// Poll the color of boards without an interrupt line.
+fn actuator_to_server(domo: Arc<Mutex<Domo>>,
+                      polled: Vec<usize>,
+                      tx_msg_to_server: Arc<Mutex<Sender<String>>>) {
     loop {
         thread::sleep(time::Duration::from_secs(COLOR_READ_TIMEOUT));
 
         let mut domo = domo.lock().unwrap();
+        for &index in polled.iter() {
+            report_color(&mut domo.boards[index], &tx_msg_to_server);
+        }
+    }
+}
 
-        let color_raw = match domo.peripheral.read_number(CMD_COLOR, 4) {
-            Ok(val) => val,
+// Wait for the interrupt line of a board and handle the events it signals.
+fn events_to_server(domo: Arc<Mutex<Domo>>,
+                    index: usize,
+                    mut irq: IrqLine,
+                    tx_msg_to_server: Arc<Mutex<Sender<String>>>) {
+    // Set when the line stays active without pending events, so the next wait
+    // doesn't return right away.
+    let mut stuck = false;
+    loop {
+        // Also check for events every once in a while, in case an interrupt
+        // is missed.
+        let timeout = time::Duration::from_secs(IRQ_TIMEOUT);
+        let result = if stuck {
+            irq.wait_edge(timeout)
+        } else {
+            irq.wait(timeout)
+        };
+        match result {
+            Ok(_) => {}
+            Err(err) => {
+                println!("WARNING: could not wait for interrupt: {}", err);
+                thread::sleep(time::Duration::from_secs(COLOR_READ_TIMEOUT));
+            }
+        }
+
+        let mut domo = domo.lock().unwrap();
+        let board = &mut domo.boards[index];
+        let events = match board.poll(CMD_EVENTS, 2) {
+            Ok(events) => events,
             Err(err) => {
-                println!("could not read color: {}", err);
+                println!("could not read events: {}", err);
+                stuck = true;
                 continue;
             }
         };
-
-        if domo.color.raw() == color_raw {
-            continue;
+        stuck = events == 0 && irq.is_active().unwrap_or(false);
+        if events & EVENT_COLOR != 0 {
+            report_color(board, &tx_msg_to_server);
         }
-        domo.color.update(color_raw);
-
-        println!("color change from peripheral: {:?}", domo.color);
-        let msg = serde_json::to_string(&MsgColor {
-                message: "actuator".to_string(),
-                name: "color".to_string(),
-                value: domo.color.clone(),
-            })
-            .unwrap();
-        tx_msg_to_server.lock().unwrap().send(msg).unwrap();
     }
 }
 
@@ -244,15 +429,31 @@ fn msg_from_server(domo: Arc<Mutex<Domo>>, rx_msg_from_server: Receiver<MsgServe
             }
             let value = msg.value.unwrap();
 
-            match &name[..] {
+            let mut domo = domo.lock().unwrap();
+            let (board, actuator) = match domo.find_actuator(&name) {
+                Some(result) => result,
+                None => {
+                    println!("WARNING: unknown actuator: {}", name);
+                    continue;
+                }
+            };
+
+            match actuator {
                 "color" => {
                     println!("color change from server: {:?}", value);
-                    let mut domo = domo.lock().unwrap();
-                    domo.color = value;
-                    let color_raw = domo.color.raw();
-                    match domo.peripheral.write_number(CMD_COLOR, 4, color_raw) {
+                    if !board.capabilities.can_write(CMD_COLOR) {
+                        println!("WARNING: peripheral has no color actuator");
+                        continue;
+                    }
+                    board.color = value;
+                    let color_raw = board.color.raw();
+                    match board.peripheral.write_number(CMD_COLOR, 4, color_raw) {
                         Ok(_) => {}
-                        Err(err) => println!("ERROR writing color: {}", err),
+                        Err(err) => {
+                            // actuator_to_server will notice the peripheral has
+                            // a different color and send that to the server.
+                            println!("ERROR writing color: {}", err);
+                        }
                     };
                 }
                 _ => {
@@ -266,8 +467,14 @@ fn msg_from_server(domo: Arc<Mutex<Domo>>, rx_msg_from_server: Receiver<MsgServe
 }
 
 // Loop endlessly and send sensor data to the server.
-fn mainloop(domo: Domo) {
+fn mainloop(mut domo: Domo, control_socket: &str, server_url: &str) {
     env_logger::init().unwrap();
+    domo.started = Some(Local::now());
+
+    for board in domo.boards.iter_mut() {
+        board.discover();
+    }
+    let has_temp = domo.boards.iter().any(|b| b.capabilities.can_read(CMD_TEMP_AVG));
 
     let (tx_msg_from_server, rx_msg_from_server): (Sender<MsgServer>, Receiver<MsgServer>) =
         channel();
@@ -276,24 +483,92 @@ fn mainloop(domo: Domo) {
 
     let name = domo.get_name();
     let serial = domo.get_serial();
+    let connected = domo.connected.clone();
+    let server_url = server_url.to_string();
     thread::spawn(move || {
-        socket::Socket::connect(SERVER_URL, name, serial, rx_msg_to_server, tx_msg_from_server);
+        socket::Socket::connect(&server_url,
+                                name,
+                                serial,
+                                rx_msg_to_server,
+                                tx_msg_from_server,
+                                connected);
     });
 
     // enable locking
     let domo = Arc::new(Mutex::new(domo));
 
-    let tx_msg_to_server_clone = tx_msg_to_server.clone();
+    // Run commands from other domoc processes.
     let domo_clone = domo.clone();
-    thread::spawn(move || {
-        actuator_to_server(domo_clone, tx_msg_to_server_clone);
+    let result = control::serve(control_socket, move |args, out| {
+        let matches = match get_matches(args) {
+            Ok(matches) => matches,
+            Err(err) => {
+                let json = args.iter().any(|arg| arg == "--json");
+                return write_usage_error(out, json, &err).unwrap_or(1);
+            }
+        };
+        let mut domo = domo_clone.lock().unwrap();
+        run_command(&mut domo, &matches, out).unwrap_or(1)
     });
+    if let Err(err) = result {
+        println!("WARNING: could not open control socket: {}", err);
+    }
+
+    // Boards with an interrupt line signal color changes, the others are
+    // polled.
+    let mut polled = Vec::new();
+    for (index, board) in domo.lock().unwrap().boards.iter().enumerate() {
+        if !board.capabilities.can_read(CMD_COLOR) {
+            continue;
+        }
+        let gpio = match board.irq_gpio() {
+            Some(gpio) => gpio,
+            None => {
+                polled.push(index);
+                continue;
+            }
+        };
+        if !board.capabilities.can_read(CMD_EVENTS) {
+            println!("WARNING: peripheral {} has no event register, polling instead",
+                     board.sensor_name("color"));
+            polled.push(index);
+            continue;
+        }
+        let irq = match IrqLine::open(gpio) {
+            Ok(irq) => irq,
+            Err(err) => {
+                println!("WARNING: could not open interrupt line (GPIO {}): {}", gpio, err);
+                polled.push(index);
+                continue;
+            }
+        };
+        let tx_msg_to_server_clone = tx_msg_to_server.clone();
+        let domo_clone = domo.clone();
+        thread::spawn(move || {
+            events_to_server(domo_clone, index, irq, tx_msg_to_server_clone);
+        });
+    }
+
+    if !polled.is_empty() {
+        let tx_msg_to_server_clone = tx_msg_to_server.clone();
+        let domo_clone = domo.clone();
+        thread::spawn(move || {
+            actuator_to_server(domo_clone, polled, tx_msg_to_server_clone);
+        });
+    }
 
     let domo_clone = domo.clone();
     thread::spawn(move || {
         msg_from_server(domo_clone, rx_msg_from_server);
     });
 
+    if !has_temp {
+        println!("Peripheral has no temperature sensor.");
+        loop {
+            thread::park();
+        }
+    }
+
     println!("       Temperature:");
     log(domo.clone(), None);
     loop {
@@ -304,97 +579,926 @@ fn mainloop(domo: Domo) {
     }
 }
 
-fn main() {
-    let mut domo = match Domo::new(SPIDEV_PATH) {
-        Ok(val) => val,
-        Err(err) => {
-            println!("error: {}", err);
-            process::exit(1);
+// Update the firmware of the board with an Intel HEX file.
+fn flash_firmware(board: &mut Board, path: &str, out: &mut Write) -> Result<(), DomoError> {
+    let mut text = String::new();
+    try!(fs::File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut text))
+        .map_err(|err| DomoError::Argument(format!("{}: {}", path, err))));
+    let image = try!(ihex::parse(&text)
+        .map_err(|msg| DomoError::Argument(format!("{}: {}", path, msg))));
+
+    board.discover();
+    if !board.capabilities.can_write(CMD_BOOT_CONTROL) {
+        return Err(DomoError::Protocol("firmware has no bootloader support".to_string()));
+    }
+    flash::flash(&mut board.peripheral, &image, out)
+}
+
+// Status of the daemon, without touching the peripherals.
+fn write_status(domo: &Domo, json: bool, out: &mut Write) -> Result<(), DomoError> {
+    let started = match domo.started {
+        Some(started) => started,
+        None => return Err(DomoError::Argument("daemon is not running".to_string())),
+    };
+    let uptime = Local::now() - started;
+    let connected = *domo.connected.lock().unwrap();
+    let mut text = format!("uptime: {}h {:02}m\nserver: {}",
+                           uptime.num_hours(),
+                           uptime.num_minutes() % 60,
+                           if connected { "connected" } else { "disconnected" });
+    let mut peripherals = Vec::new();
+    for board in domo.boards.iter() {
+        match board.last_temp {
+            Some((temp, time)) => {
+                text.push_str(&format!("\n{}: {:.2}°C at {:02}:{:02}",
+                                       board.sensor_name("temp"),
+                                       temp,
+                                       time.hour(),
+                                       time.minute()))
+            }
+            None => text.push_str(&format!("\n{}: <none>", board.sensor_name("temp"))),
         }
+        let stats = board.peripheral.stats();
+        text.push_str(&format!("\n{}: {}", board.sensor_name("link"), stats));
+        peripherals.push(PeripheralStatus {
+            name: board.name.clone(),
+            temp: board.last_temp.map(|(temp, _)| temp),
+            time: board.last_temp.map(|(_, time)| time.timestamp()),
+            transactions: stats.transactions,
+            retries: stats.retries,
+            errors: stats.errors(),
+            resyncs: stats.resyncs,
+        });
+    }
+    let status = StatusOutput {
+        uptime: uptime.num_seconds(),
+        connected: connected,
+        peripherals: peripherals,
     };
+    try!(write_value(out, json, "status", status, &text));
+    Ok(())
+}
 
-    // Parse param if it exists
-    let param = match env::args().nth(2) {
-        Some(strval) => {
-            match u32::from_str_radix(strval.as_str(), 16) {
-                Ok(val) => Some(val),
-                Err(err) => {
-                    println!("Could not parse argument \"{}\": {}", strval, err);
-                    process::exit(1);
-                }
-            }
+// Write the result of a command: the text for humans, or with --json a
+// CommandOutput object on a single line.
+fn write_value<T: Serialize>(out: &mut Write,
+                             json: bool,
+                             command: &str,
+                             value: T,
+                             text: &str)
+                             -> io::Result<()> {
+    if !json {
+        return writeln!(out, "{}", text);
+    }
+    let output = CommandOutput {
+        command: command.to_string(),
+        ok: true,
+        value: Some(value),
+        error: None,
+        status: 0,
+    };
+    writeln!(out, "{}", serde_json::to_string(&output).unwrap())
+}
+
+// Write the error of a failed command. Returns the exit status.
+fn write_error(out: &mut Write, json: bool, command: &str, err: &DomoError) -> io::Result<i32> {
+    if !json {
+        if command.is_empty() {
+            try!(writeln!(out, "error: {}", err));
+        } else {
+            try!(writeln!(out, "{}: error: {}", command, err));
         }
-        None => None,
+        return Ok(err.exit_code());
+    }
+    let output: CommandOutput<()> = CommandOutput {
+        command: command.to_string(),
+        ok: false,
+        value: None,
+        error: Some(err.to_string()),
+        status: err.exit_code(),
     };
+    try!(writeln!(out, "{}", serde_json::to_string(&output).unwrap()));
+    Ok(err.exit_code())
+}
 
-    match env::args().nth(1) {
-        Some(ref cmd) if cmd == "resync" => {
-            print!("resync: ");
-            match domo.resync() {
-                Ok(_) => println!("done"),
-                Err(err) => {
-                    println!(" error: {}", err);
-                    process::exit(1);
+// Report an invalid command line: clap's message with the usage, or with
+// --json an error object like for any other error. Returns the exit status.
+fn write_usage_error(out: &mut Write, json: bool, err: &clap::Error) -> io::Result<i32> {
+    if !json {
+        try!(writeln!(out, "{}", err.message));
+        return Ok(1);
+    }
+    // Leave out the usage, which follows an empty line.
+    let msg = err.message.split("\n\n").next().unwrap_or("").trim_left_matches("error: ");
+    let msg = msg.split_whitespace().collect::<Vec<_>>().join(" ");
+    write_error(out, json, "", &DomoError::Argument(msg))
+}
+
+// Parse a command line. With --json clap doesn't color its messages, so they
+// can be put in an error object as they are.
+fn get_matches<I, T>(args: I) -> Result<ArgMatches<'static>, clap::Error>
+    where I: IntoIterator<Item = T>,
+          T: Into<OsString>
+{
+    let args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+    let mut app = app();
+    if args.iter().any(|arg| arg.to_str() == Some("--json")) {
+        app = app.global_setting(AppSettings::ColorNever);
+    }
+    app.get_matches_from_safe(args)
+}
+
+// Run a one-shot command and write its output. Returns the exit status. A board
+// selected with --peripheral is only used for this command.
+fn run_command(domo: &mut Domo, matches: &ArgMatches, out: &mut Write) -> io::Result<i32> {
+    let previous = domo.selected;
+    let result = run_selected_command(domo, matches, out);
+    domo.selected = previous;
+    result
+}
+
+fn run_selected_command(domo: &mut Domo,
+                        matches: &ArgMatches,
+                        out: &mut Write)
+                        -> io::Result<i32> {
+    let (cmd, args) = matches.subcommand();
+    let json = matches.is_present("json");
+    let args = match args {
+        Some(args) => args,
+        None => {
+            let err = DomoError::Argument("no command given".to_string());
+            return write_error(out, json, cmd, &err);
+        }
+    };
+    let selected = match matches.value_of("peripheral") {
+        Some(name) => domo.select(name),
+        None => Ok(()),
+    };
+    let status = match selected.and_then(|_| run_subcommand(domo, cmd, args, json, out)) {
+        Ok(status) => status,
+        Err(err) => try!(write_error(out, json, cmd, &err)),
+    };
+    if matches.is_present("verbose") && !json {
+        try!(writeln!(out, "link: {}", domo.board().peripheral.stats()));
+    }
+    Ok(status)
+}
+
+fn run_subcommand(domo: &mut Domo,
+                  cmd: &str,
+                  args: &ArgMatches,
+                  json: bool,
+                  out: &mut Write)
+                  -> Result<i32, DomoError> {
+    match cmd {
+        "resync" => {
+            try!(domo.resync());
+            try!(write_value(out, json, cmd, (), "resync: done"));
+        }
+        "test" => {
+            let val = try!(domo.read_number(CMD_TEST, 2));
+            try!(write_value(out, json, cmd, val, &format!("test 2: {:04x}", val)));
+        }
+        "test4" => {
+            let val = try!(domo.read_number(CMD_TEST, 4));
+            try!(write_value(out, json, cmd, val, &format!("test 4: {:08x}", val)));
+        }
+        "temp" => {
+            let temp = try!(domo.read_temp(CMD_TEMP_AVG));
+            try!(write_value(out, json, cmd, temp.celsius, &format!("temp avg: {}", temp)));
+        }
+        "temp-now" => {
+            let temp = try!(domo.read_temp(CMD_TEMP_NOW));
+            try!(write_value(out, json, cmd, temp.celsius, &format!("temp now: {}", temp)));
+        }
+        "temp-rsum" => {
+            let temp = try!(domo.read_temp_rsum());
+            try!(write_value(out, json, cmd, temp.celsius, &format!("temp rsum: {}", temp)));
+        }
+        "temp-raw" => {
+            let temp = try!(domo.read_temp_raw());
+            try!(write_value(out, json, cmd, temp.celsius, &format!("temp raw: {}", temp)));
+        }
+        "version" => {
+            let val = try!(domo.board().peripheral.read_string(CMD_FIRMWARE_VERSION));
+            let text = format!("firmware version: {}", val);
+            try!(write_value(out, json, cmd, val, &text));
+        }
+        "status" => try!(write_status(domo, json, out)),
+        "flash" => {
+            let path = args.value_of("hex-file").unwrap();
+            if json {
+                // Leave out the progress, so the output stays valid JSON.
+                try!(flash_firmware(domo.board(), path, &mut io::sink()));
+            } else {
+                try!(flash_firmware(domo.board(), path, out));
+            }
+            try!(write_value(out, json, cmd, (), "flash: done"));
+        }
+        "reg" => {
+            let (sub, args) = args.subcommand();
+            let args = args.unwrap();
+            let (id, width) = try!(parse_register(args));
+            let val = if sub == "set" {
+                let val = try!(parse_u64(args.value_of("value").unwrap()));
+                if width < 8 && val >> (width * 8) != 0 {
+                    return Err(DomoError::Argument(format!("{:#x} doesn't fit in {} bytes",
+                                                           val,
+                                                           width)));
                 }
+                try!(domo.board().peripheral.write_register(id, width, val));
+                val
+            } else {
+                try!(domo.board().peripheral.read_register(id, width))
             };
+            let text = format!("reg {:#04x}: {} = {:#0hex$x} = {:#0bin$b}",
+                               id,
+                               val,
+                               val,
+                               val,
+                               hex = width as usize * 2 + 2,
+                               bin = width as usize * 8 + 2);
+            try!(write_value(out, json, cmd, val, &text));
         }
-        Some(ref cmd) if cmd == "test2" || cmd == "test" => {
-            match domo.read_number(CMD_TEST, 2) {
-                Ok(val) => println!("test 2: {:04x}", val),
-                Err(err) => println!("test 2: error: {}", err),
+        "color" => {
+            // Parse param if it exists
+            let param = match args.values_of("value") {
+                Some(values) => Some(try!(parse_color(&values.collect::<Vec<_>>()))),
+                None => None,
             };
-        }
-        Some(ref cmd) if cmd == "test4" => {
-            match domo.read_number(CMD_TEST, 4) {
-                Ok(val) => println!("test 4: {:08x}", val),
-                Err(err) => println!("test 4: error: {}", err),
+
+            let val = match param {
+                Some(param) => {
+                    try!(domo.write_number(CMD_COLOR, 4, param));
+                    if !json {
+                        return Ok(0);
+                    }
+                    param
+                }
+                None => try!(domo.read_number(CMD_COLOR, 4)),
             };
-        }
-        Some(ref cmd) if cmd == "temp" || cmd == "temp-avg" => {
-            match domo.read_number(CMD_TEMP_AVG, 2) {
-                Ok(val) => println!("temp avg: {:.2}°C", decode_temp(val)),
-                Err(err) => println!("temp avg: error: {}", err),
+            let color = Color::from_raw(val);
+            let text = format!("color: {:08x}: {:?}", val, color);
+            let output = ColorOutput {
+                raw: format!("{:08x}", val),
+                color: color,
             };
+            try!(write_value(out, json, cmd, output, &text));
         }
-        Some(ref cmd) if cmd == "temp-now" => {
-            match domo.read_number(CMD_TEMP_NOW, 2) {
-                Ok(val) => println!("temp now: {:.2}°C", decode_temp(val)),
-                Err(err) => println!("temp now: error: {}", err),
+        "bench" => {
+            let speeds = try!(parse_list(args.value_of("speeds")));
+            let delays = match args.value_of("delays") {
+                Some(delays) => {
+                    try!(parse_list(Some(delays)))
+                        .iter()
+                        .map(|delay| time::Duration::new(*delay as u64 / 1000000,
+                                                         delay % 1000000 * 1000))
+                        .collect()
+                }
+                None => vec![domo.board().peripheral.byte_delay()],
             };
+            let transactions = try!(parse_number(args.value_of("transactions").unwrap()));
+            let runs = try!(bench::run(&mut domo.board().peripheral,
+                                       &speeds,
+                                       &delays,
+                                       transactions));
+
+            let mut text = format!("{:>10} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>6} {:>8} {:>6}",
+                                   "speed Hz",
+                                   "delay us",
+                                   "trans/s",
+                                   "p50 ms",
+                                   "p90 ms",
+                                   "p99 ms",
+                                   "max ms",
+                                   "CRC",
+                                   "framing",
+                                   "failed");
+            for run in runs.iter() {
+                text.push_str(&format!("\n{:>10} {:>8} {:>8.1} {:>8.2} {:>8.2} {:>8.2} {:>8.2} \
+                                        {:>6} {:>8} {:>6}",
+                                       run.speed_hz
+                                           .map(|speed| speed.to_string())
+                                           .unwrap_or("current".to_string()),
+                                       run.byte_delay_us,
+                                       run.per_second,
+                                       run.latency_p50_ms,
+                                       run.latency_p90_ms,
+                                       run.latency_p99_ms,
+                                       run.latency_max_ms,
+                                       run.crc_errors,
+                                       run.framing_errors,
+                                       run.failures));
+            }
+            try!(write_value(out, json, cmd, runs, &text));
         }
-        Some(ref cmd) if cmd == "temp-rsum" => {
-            match domo.read_temp_rsum() {
-                Ok(val) => println!("temp rsum: {:.2}°C", val),
-                Err(err) => println!("temp rsum: error: {}", err),
+        "diagnose" => {
+            let checks = diagnose::run(domo.board());
+            let failed = checks.iter().filter(|check| !check.ok).count();
+            let mut text = String::new();
+            for check in checks.iter() {
+                text.push_str(&format!("{:<24} {} ({})\n",
+                                       check.name,
+                                       if check.ok { "ok" } else { "FAIL" },
+                                       check.detail));
+            }
+            if failed == 0 {
+                text.push_str("result: PASS");
+            } else {
+                text.push_str(&format!("result: FAIL ({} of {} checks)", failed, checks.len()));
+            }
+            let output = DiagnoseOutput {
+                passed: failed == 0,
+                checks: checks,
             };
+            try!(write_value(out, json, cmd, output, &text));
+            if failed > 0 {
+                return Ok(DIAGNOSE_FAILED);
+            }
         }
-        Some(ref cmd) if cmd == "temp-raw" => {
-            match domo.read_temp_raw() {
-                Ok(val) => println!("temp raw: {:.2}°C", val),
-                Err(err) => println!("temp raw: error: {}", err),
-            };
+        "watch" => try!(watch(domo, args, json, out)),
+        "calibrate" => try!(calibrate(domo, json, out)),
+        "shell" => return Err(DomoError::Argument("already running a shell".to_string())),
+        _ => unreachable!(),
+    }
+    Ok(0)
+}
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+// Read the value to watch once. Returns the value and how to print it.
+fn watch_sample(domo: &mut Domo,
+                what: &str,
+                args: &ArgMatches)
+                -> Result<(f64, String), DomoError> {
+    let temp = match what {
+        "temp" | "temp-avg" => try!(domo.read_temp(CMD_TEMP_AVG)),
+        "temp-now" => try!(domo.read_temp(CMD_TEMP_NOW)),
+        "temp-raw" => try!(domo.read_temp_raw()),
+        "temp-rsum" => try!(domo.read_temp_rsum()),
+        "color" => {
+            let val = try!(domo.read_number(CMD_COLOR, 4));
+            return Ok((val as f64, format!("{:08x}", val)));
         }
-        Some(ref cmd) if cmd == "color" => {
-            match param {
-                Some(param) => {
-                    match domo.write_number(CMD_COLOR, 4, param) {
-                        Ok(_) => {}
-                        Err(err) => println!("ERROR writing color: {}", err),
-                    };
+        "reg" => {
+            let (id, width) = try!(parse_register(args));
+            let val = try!(domo.board().peripheral.read_register(id, width));
+            let text = format!("{} = {:#0hex$x}", val, val, hex = width as usize * 2 + 2);
+            return Ok((val as f64, text));
+        }
+        _ => return Err(DomoError::Argument(format!("can't watch \"{}\"", what))),
+    };
+    Ok((temp.celsius, temp.to_string()))
+}
+
+// Poll a value and print every reading with a timestamp, until interrupted or
+// until --count readings are done. Changed values are marked, and with
+// --stats the running minimum, maximum and mean are printed as well.
+fn watch(domo: &mut Domo, args: &ArgMatches, json: bool, out: &mut Write) -> Result<(), DomoError> {
+    if domo.started.is_some() {
+        return Err(DomoError::Argument("watch doesn't run in the daemon".to_string()));
+    }
+    let what = args.value_of("what").unwrap();
+    if what == "reg" {
+        try!(parse_register(args));
+    }
+    let interval = time::Duration::from_millis(try!(parse_number(args.value_of("interval")
+        .unwrap())) as u64);
+    let count = match args.value_of("count") {
+        Some(count) => Some(try!(parse_number(count))),
+        None => None,
+    };
+    let show_stats = args.is_present("stats") && what != "color";
+    let precision = if what.starts_with("temp") { 2 } else { 0 };
+    let highlight = !json && unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    let handler = on_interrupt as extern "C" fn(libc::c_int);
+    unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
+
+    let mut previous = None;
+    let (mut min, mut max, mut sum, mut readings) = (0.0f64, 0.0f64, 0.0, 0);
+    let mut samples = 0;
+    let mut result = Ok(());
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        let now = Local::now();
+        match watch_sample(domo, what, args) {
+            Ok((val, text)) => {
+                let changed = previous.map_or(false, |previous| previous != val);
+                previous = Some(val);
+                if readings == 0 || val < min {
+                    min = val;
                 }
-                None => {
-                    match domo.read_number(CMD_COLOR, 4) {
-                        Ok(val) => println!("color: {:08x}: {:?}", val, Color::from_raw(val)),
-                        Err(err) => println!("color: error: {}", err),
+                if readings == 0 || val > max {
+                    max = val;
+                }
+                sum += val;
+                readings += 1;
+                let mean = sum / readings as f64;
+
+                if json {
+                    let output = WatchOutput {
+                        time: now.to_rfc3339(),
+                        value: val,
+                        text: text,
+                        changed: changed,
+                        min: if show_stats { Some(min) } else { None },
+                        max: if show_stats { Some(max) } else { None },
+                        mean: if show_stats { Some(mean) } else { None },
+                    };
+                    result = write_value(out, json, "watch", output, "");
+                } else {
+                    let mut line = format!("{} {}: {}", now.format("%H:%M:%S"), what, text);
+                    if show_stats {
+                        line.push_str(&format!("  (min {:.*}, max {:.*}, mean {:.*})",
+                                               precision,
+                                               min,
+                                               precision,
+                                               max,
+                                               precision + 2,
+                                               mean));
+                    }
+                    result = if changed && highlight {
+                        writeln!(out, "* \x1b[1m{}\x1b[0m", line)
+                    } else if changed {
+                        writeln!(out, "* {}", line)
+                    } else {
+                        writeln!(out, "  {}", line)
                     };
                 }
-            };
+            }
+            Err(err) => {
+                result = if json {
+                    write_error(out, json, "watch", &err).map(|_| ())
+                } else {
+                    writeln!(out, "  {} {}: error: {}", now.format("%H:%M:%S"), what, err)
+                };
+            }
         }
-        Some(ref cmd) => {
-            println!("unknown command: {}", cmd);
+        if result.is_ok() {
+            result = out.flush();
         }
-        None => {
-            mainloop(domo);
+        samples += 1;
+        if result.is_err() || count.map_or(false, |count| samples >= count) {
+            break;
+        }
+        thread::sleep(interval);
+    }
+
+    unsafe { libc::signal(libc::SIGINT, libc::SIG_DFL) };
+    result.map_err(DomoError::from)
+}
+
+// Calculate the Steinhart–Hart coefficients of the thermistor from three
+// reference temperatures entered by the user, measuring the resistance of the
+// NTC at each of them.
+fn calibrate(domo: &mut Domo, json: bool, out: &mut Write) -> Result<(), DomoError> {
+    if domo.started.is_some() {
+        return Err(DomoError::Argument("calibrate doesn't run in the daemon".to_string()));
+    }
+    let stdin = io::stdin();
+    let mut stderr = io::stderr();
+    let mut points = [(0.0, 0.0); 3];
+    for (i, point) in points.iter_mut().enumerate() {
+        // With --json only the result goes to stdout.
+        let prompt: &mut Write = if json { &mut stderr } else { &mut *out };
+        let temp;
+        loop {
+            try!(write!(prompt,
+                        "Bring the sensor to a known temperature and enter it in °C (point {} of \
+                         3): ",
+                        i + 1));
+            try!(prompt.flush());
+            let mut line = String::new();
+            if try!(stdin.lock().read_line(&mut line)) == 0 {
+                return Err(DomoError::Argument("calibration aborted".to_string()));
+            }
+            match line.trim().parse() {
+                Ok(val) => {
+                    temp = val;
+                    break;
+                }
+                Err(_) => try!(writeln!(prompt, "not a number: {}", line.trim())),
+            }
+        }
+        let resistance = try!(domo.board().read_temp_resistance());
+        try!(writeln!(prompt, "{:.2}°C at {:.0}Ω", temp, resistance));
+        *point = (temp, resistance);
+    }
+
+    let (a, b, c) = try!(thermistor::fit_steinhart_hart(&points).map_err(DomoError::Argument));
+    let lines: Vec<String> = [("temp_sh_a", a), ("temp_sh_b", b), ("temp_sh_c", c)]
+        .iter()
+        .map(|&(name, value)| format!("    \"{}\": {:e}", name, value))
+        .collect();
+    let text = format!("Add these coefficients to the configuration:\n{}", lines.join(",\n"));
+    let output = CalibrateOutput {
+        temp_sh_a: a,
+        temp_sh_b: b,
+        temp_sh_c: c,
+    };
+    try!(write_value(out, json, "calibrate", output, &text));
+    Ok(())
+}
+
+// Interactive prompt that runs commands on the open peripheral, so the device
+// isn't opened again and the thermistor constants are read only once.
+fn shell(domo: &mut Domo) {
+    let history_path = env::home_dir().map(|path| path.join(HISTORY_PATH));
+    let mut editor = rustyline::Editor::<()>::new();
+    if let Some(ref path) = history_path {
+        let _ = editor.load_history(path);
+    }
+
+    loop {
+        let line = match editor.readline("domoc> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                println!("error: {}", err);
+                break;
+            }
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line.trim());
+        if words[0] == "exit" || words[0] == "quit" {
+            break;
+        }
+
+        let matches = match get_matches(iter::once("domoc").chain(words)) {
+            Ok(matches) => matches,
+            Err(err) => {
+                println!("{}", err.message);
+                continue;
+            }
+        };
+        if matches.subcommand_name().is_none() {
+            continue;
+        }
+        let stdout = io::stdout();
+        if let Err(err) = run_command(domo, &matches, &mut stdout.lock()) {
+            println!("error: {}", err);
+        }
+    }
+
+    if let Some(ref path) = history_path {
+        if let Err(err) = editor.save_history(path) {
+            println!("WARNING: could not save history: {}", err);
+        }
+    }
+}
+
+// Command line interface. Without a subcommand domoc runs as a daemon.
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("domoc")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Connects a domo-avr board to the domos home automation server")
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(Arg::with_name("config")
+            .long("config")
+            .value_name("FILE")
+            .global(true)
+            .help("Configuration file (default: ~/.config/domo.json)"))
+        .arg(Arg::with_name("device")
+            .long("device")
+            .value_name("PATH")
+            .global(true)
+            .help("SPI device of the peripheral given with --peripheral, or else of \
+                   peripherals without a device in the config (default: /dev/spidev0.0)"))
+        .arg(Arg::with_name("peripheral")
+            .short("p")
+            .long("peripheral")
+            .value_name("NAME")
+            .global(true)
+            .help("Peripheral to run the command on, when there are several (default: the \
+                   first)"))
+        .arg(Arg::with_name("server")
+            .long("server")
+            .value_name("URL")
+            .global(true)
+            .help("WebSocket URL of the server (default: wss://domo.aykevl.nl/api/ws/device)"))
+        .arg(Arg::with_name("verbose")
+            .short("v")
+            .long("verbose")
+            .global(true)
+            .help("Log more details, and print link statistics after a command"))
+        .arg(Arg::with_name("json")
+            .long("json")
+            .global(true)
+            .help("Print the result of a command, or its error, as a JSON object"))
+        .subcommand(SubCommand::with_name("resync").about("Resynchronize with the peripheral"))
+        .subcommand(SubCommand::with_name("test")
+            .alias("test2")
+            .about("Read the 2-byte test register"))
+        .subcommand(SubCommand::with_name("test4").about("Read the 4-byte test register"))
+        .subcommand(SubCommand::with_name("temp")
+            .alias("temp-avg")
+            .about("Read the average temperature"))
+        .subcommand(SubCommand::with_name("temp-now").about("Read the current temperature"))
+        .subcommand(SubCommand::with_name("temp-rsum")
+            .about("Calculate the temperature from the sum of raw readings"))
+        .subcommand(SubCommand::with_name("temp-raw")
+            .about("Calculate the temperature from a raw reading"))
+        .subcommand(SubCommand::with_name("version").about("Read the firmware version"))
+        .subcommand(SubCommand::with_name("status")
+            .about("Show the state of the running daemon"))
+        .subcommand(SubCommand::with_name("flash")
+            .about("Update the firmware of the peripheral")
+            .arg(Arg::with_name("hex-file")
+                .required(true)
+                .help("Firmware in Intel HEX format")))
+        .subcommand(SubCommand::with_name("reg")
+            .about("Read or write any register, e.g. when debugging new firmware")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("get")
+                .about("Read a register")
+                .arg(Arg::with_name("id")
+                    .required(true)
+                    .help(REGISTER_HELP))
+                .arg(width_arg()))
+            .subcommand(SubCommand::with_name("set")
+                .about("Write a register")
+                .arg(Arg::with_name("id")
+                    .required(true)
+                    .help(REGISTER_HELP))
+                .arg(Arg::with_name("value")
+                    .required(true)
+                    .help("New value, in decimal or hex (0xabcd)"))
+                .arg(width_arg())))
+        .subcommand(SubCommand::with_name("color")
+            .about("Read the color, or set it when a value is given")
+            .arg(Arg::with_name("value")
+                .multiple(true)
+                .help("Raw color value in hex (4148ffff), or hsv or rgb followed by three \
+                       values between 0 and 1 (hsv 0.3 1 1)")))
+        .subcommand(SubCommand::with_name("diagnose")
+            .about("Check the link and the sensors of a new board"))
+        .subcommand(SubCommand::with_name("bench")
+            .about("Measure the speed and error rate of the link")
+            .arg(Arg::with_name("speeds")
+                .long("speeds")
+                .value_name("HZ,...")
+                .help("SPI clock speeds to try (default: the current speed)"))
+            .arg(Arg::with_name("delays")
+                .long("delays")
+                .value_name("US,...")
+                .help("Delays before every byte in microseconds (default: the current delay)"))
+            .arg(Arg::with_name("transactions")
+                .long("transactions")
+                .value_name("N")
+                .default_value("200")
+                .help("Number of reads for every speed and delay")))
+        .subcommand(SubCommand::with_name("watch")
+            .about("Read a value repeatedly and print every reading")
+            .arg(Arg::with_name("what")
+                .required(true)
+                .possible_values(WATCH_VALUES)
+                .help("Value to watch"))
+            .arg(Arg::with_name("id").help(REGISTER_HELP))
+            .arg(width_arg())
+            .arg(Arg::with_name("interval")
+                .long("interval")
+                .value_name("MS")
+                .default_value("1000")
+                .help("Time between readings in milliseconds"))
+            .arg(Arg::with_name("count")
+                .long("count")
+                .value_name("N")
+                .help("Stop after this many readings (default: until Ctrl-C)"))
+            .arg(Arg::with_name("stats")
+                .long("stats")
+                .help("Print the minimum, maximum and mean of the readings so far")))
+        .subcommand(SubCommand::with_name("calibrate")
+            .about("Calculate thermistor coefficients from three reference temperatures"))
+        .subcommand(SubCommand::with_name("shell")
+            .about("Run commands interactively, keeping the peripheral open"))
+}
+
+fn width_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("width")
+        .long("width")
+        .value_name("BYTES")
+        .possible_values(&["1", "2", "3", "4", "8"])
+        .default_value("2")
+        .help("Size of the register, other sizes than 2 and 4 use an extended frame")
+}
+
+// Use another device for the named peripheral.
+fn set_device(config: &mut Config, peripheral: &str, device: &str) -> Result<(), DomoError> {
+    let config = config.peripherals
+        .as_mut()
+        .and_then(|peripherals| peripherals.iter_mut().find(|p| p.name == peripheral));
+    match config {
+        Some(config) => {
+            config.device = Some(device.to_string());
+            Ok(())
+        }
+        None => Err(DomoError::Argument(format!("unknown peripheral: {}", peripheral))),
+    }
+}
+
+// Report an error that happened before the command could run, and exit.
+fn fail(matches: &ArgMatches, err: DomoError) -> ! {
+    let stdout = io::stdout();
+    let command = matches.subcommand_name().unwrap_or("");
+    let _ = write_error(&mut stdout.lock(), matches.is_present("json"), command, &err);
+    process::exit(err.exit_code());
+}
+
+fn main() {
+    let matches = match get_matches(env::args_os()) {
+        Ok(matches) => matches,
+        // Help and version aren't errors, they're printed as usual.
+        Err(ref err) if err.use_stderr() && env::args().any(|arg| arg == "--json") => {
+            let stdout = io::stdout();
+            process::exit(write_usage_error(&mut stdout.lock(), true, err).unwrap_or(1));
+        }
+        Err(err) => err.exit(),
+    };
+
+    let mut config = match load_config(matches.value_of("config")) {
+        Ok(val) => val,
+        Err(err) => fail(&matches, err),
+    };
+    let control_socket = config.control_socket
+        .clone()
+        .unwrap_or(control::DEFAULT_SOCKET_PATH.to_string());
+
+    // Let the daemon run the command, if it is running. The daemon uses its
+    // own device, so commands for another device are run here.
+    // Interactive and long-running commands can't go through the daemon.
+    let local = match matches.subcommand_name() {
+        None | Some("shell") | Some("watch") | Some("bench") | Some("calibrate") => true,
+        Some(_) => matches.is_present("device"),
+    };
+    // The clock speed is a setting of the SPI device, so changing it would
+    // disturb the daemon.
+    let speeds = matches.subcommand_matches("bench").map_or(false, |m| m.is_present("speeds"));
+    if speeds && control::is_running(&control_socket) {
+        fail(&matches,
+             DomoError::Argument("can't change the clock speed while the daemon is running"
+                 .to_string()));
+    }
+    if !local {
+        let mut args: Vec<String> = env::args().collect();
+        let flash_path = matches.subcommand_matches("flash").and_then(|m| m.value_of("hex-file"));
+        if let Some(path) = flash_path {
+            // The daemon may run in a different directory.
+            if let Ok(abspath) = fs::canonicalize(path) {
+                for arg in args.iter_mut().filter(|arg| *arg == path) {
+                    *arg = abspath.to_string_lossy().into_owned();
+                }
+            }
+        }
+        match control::send(&control_socket, &args) {
+            Some(Ok(response)) => {
+                print!("{}", response.output);
+                process::exit(response.status);
+            }
+            Some(Err(err)) => fail(&matches, err),
+            None => {}
+        }
+    }
+
+    let mut spidev_path = matches.value_of("device").unwrap_or(SPIDEV_PATH);
+    if let (Some(name), Some(device)) = (matches.value_of("peripheral"),
+                                         matches.value_of("device")) {
+        if let Err(err) = set_device(&mut config, name, device) {
+            fail(&matches, err);
+        }
+        spidev_path = SPIDEV_PATH;
+    }
+    let mut domo = match Domo::new(config, spidev_path) {
+        Ok(val) => val,
+        Err(err) => fail(&matches, err),
+    };
+    if let Some(name) = matches.value_of("peripheral") {
+        if let Err(err) = domo.select(name) {
+            fail(&matches, err);
+        }
+    }
+
+    if matches.subcommand_name().is_none() {
+        if matches.is_present("verbose") && env::var("RUST_LOG").is_err() {
+            env::set_var("RUST_LOG", "info");
+        }
+        mainloop(domo,
+                 &control_socket,
+                 matches.value_of("server").unwrap_or(SERVER_URL));
+        return;
+    }
+
+    if matches.subcommand_name() == Some("shell") {
+        shell(&mut domo);
+        return;
+    }
+
+    let stdout = io::stdout();
+    match run_command(&mut domo, &matches, &mut stdout.lock()) {
+        Ok(status) => process::exit(status),
+        Err(err) => {
+            println!("error: {}", err);
+            process::exit(1);
         }
     }
 }
+
+#[test]
+fn test_parse_number() {
+    assert_eq!(parse_number("18").unwrap(), 18);
+    assert_eq!(parse_number("0x12").unwrap(), 18);
+    assert_eq!(parse_number("0xABCD").unwrap(), 0xabcd);
+    assert!(parse_number("0x").is_err());
+    assert!(parse_number("12a").is_err());
+}
+
+#[test]
+fn test_parse_register() {
+    let matches = app().get_matches_from(vec!["domoc", "reg", "get", "0x12", "--width", "4"]);
+    let args = matches.subcommand_matches("reg").unwrap().subcommand_matches("get").unwrap();
+    assert_eq!(parse_register(args).unwrap(), (0x12, 4));
+    for id in &["0x3f", "0x40"] {
+        let matches = app().get_matches_from(vec!["domoc", "reg", "get", id]);
+        let args = matches.subcommand_matches("reg").unwrap().subcommand_matches("get").unwrap();
+        assert!(parse_register(args).is_err());
+    }
+}
+
+#[test]
+fn test_reg_width() {
+    let mut domo = Domo::emulated(&[""]);
+    for &(command, expected) in &[("set 0x21 0x0123456789abcdef --width 8",
+                                   "reg 0x21: 81985529216486895 = 0x0123456789abcdef"),
+                                  ("get 0x21 --width 8",
+                                   "reg 0x21: 81985529216486895 = 0x0123456789abcdef"),
+                                  ("get 0x05 --width 4", "reg 0x05: 0 = 0x00000000")] {
+        let mut args = vec!["domoc", "reg"];
+        args.extend(command.split(' '));
+        let matches = app().get_matches_from(args);
+        let mut out = Vec::new();
+        assert_eq!(run_command(&mut domo, &matches, &mut out).unwrap(), 0);
+        assert!(String::from_utf8(out).unwrap().starts_with(expected));
+    }
+}
+
+#[test]
+fn test_parse_color() {
+    assert_eq!(parse_color(&["4148ffff"]).unwrap(), 0x4148ffff);
+    assert_eq!(parse_color(&["hsv", "0", "1", "1"]).unwrap(), 0x0100ffff);
+    assert_eq!(parse_color(&["rgb", "1", "0", "0.5"]).unwrap(), 0x00ff0080);
+    assert!(parse_color(&["hsv", "0.3", "2", "1"]).is_err());
+    assert!(parse_color(&["hsv", "0.3"]).is_err());
+}
+
+#[test]
+fn test_watch_sample() {
+    let mut domo = Domo::emulated(&[""]);
+    for what in WATCH_VALUES {
+        let matches = app().get_matches_from(vec!["domoc", "watch", what, "0x20"]);
+        let args = matches.subcommand_matches("watch").unwrap();
+        assert!(watch_sample(&mut domo, what, args).is_ok(), "watch {}", what);
+    }
+    let matches = app().get_matches_from(vec!["domoc", "watch", "temp"]);
+    let args = matches.subcommand_matches("watch").unwrap();
+    assert!(watch_sample(&mut domo, "bogus", args).is_err());
+}
+
+#[test]
+fn test_run_command_without_subcommand() {
+    let mut domo = Domo::emulated(&[""]);
+    let matches = app().get_matches_from(vec!["domoc", "--json"]);
+    let mut out = Vec::new();
+    assert_eq!(run_command(&mut domo, &matches, &mut out).unwrap(), 1);
+    assert!(String::from_utf8(out).unwrap().contains("no command given"));
+}
+
+#[test]
+fn test_select_peripheral() {
+    let config = r#"{"name": "", "serial": "", "peripherals": [{"name": "desk"},
+                                                                {"name": "window"}]}"#;
+    let mut config = Config::parse(config).unwrap();
+    set_device(&mut config, "window", "/dev/spidev0.1").unwrap();
+    assert!(set_device(&mut config, "door", "/dev/spidev0.1").is_err());
+    assert_eq!(config.peripherals.as_ref().unwrap()[1].device,
+               Some("/dev/spidev0.1".to_string()));
+
+    let mut domo = Domo::emulated(&["desk", "window"]);
+    assert_eq!(domo.board().name, "desk");
+    domo.write_number(CMD_COLOR, 4, 0x4148ffff).unwrap();
+
+    let matches = app().get_matches_from(vec!["domoc", "--peripheral", "window", "color"]);
+    let mut out = Vec::new();
+    assert_eq!(run_command(&mut domo, &matches, &mut out).unwrap(), 0);
+    assert_eq!(String::from_utf8(out).unwrap(),
+               format!("color: 00000000: {:?}\n", Color::from_raw(0)));
+    // The selection doesn't stick to later commands, like in the shell.
+    assert_eq!(domo.board().name, "desk");
+    assert!(domo.select("door").is_err());
+}
@@ -1,3 +1,4 @@
+use serde_json;
 
 // Message received from server
 #[derive(Deserialize,Debug)]
@@ -33,9 +34,163 @@ pub struct MsgSensorLog {
 pub struct Config {
     pub name: String,
     pub serial: String,
+    pub control_socket: Option<String>,
+    // Without a `peripherals` list, the settings of a single unnamed
+    // peripheral are at the top level, see Config::parse.
+    pub peripherals: Option<Vec<PeripheralConfig>>,
+}
+
+// Config of one peripheral (AVR) connected to this controller
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PeripheralConfig {
+    pub name: String,
+    pub transport: Option<String>,
+    pub device: Option<String>,
+    pub baud_rate: Option<u32>,
+    pub spi_speed_hz: Option<u32>,
+    pub spi_mode: Option<u8>,
+    pub spi_bits_per_word: Option<u8>,
+    pub byte_delay_us: Option<u64>,
+    pub batch_transfers: Option<bool>,
+    pub trace: Option<String>,
+    pub irq_gpio: Option<u32>,
+    pub lock_file: Option<String>,
+    pub lock_timeout_ms: Option<u64>,
+    pub retries: Option<u32>,
+    pub retry_backoff_ms: Option<u64>,
+    pub resync_after: Option<u32>,
+    pub verify_writes: Option<bool>,
     pub temp_b_coefficient: Option<f64>,
     pub temp_nominal_r: Option<f64>,
     pub temp_series_resistor: Option<f64>,
+    pub temp_sh_a: Option<f64>,
+    pub temp_sh_b: Option<f64>,
+    pub temp_sh_c: Option<f64>,
+    pub temp_offset: Option<f64>,
+    pub temp_gain: Option<f64>,
+    pub emulator_crc_error_every: Option<u32>,
+    pub emulator_dropped_write_every: Option<u32>,
+    pub emulator_desync_every: Option<u32>,
+    pub emulator_button_interval_ms: Option<u64>,
+}
+
+impl Config {
+    // Parse the config file. Without a `peripherals` list, the top-level
+    // object is read as the config of a single unnamed peripheral as well.
+    pub fn parse(text: &str) -> Result<Config, serde_json::Error> {
+        let mut config: Config = try!(serde_json::from_str(text));
+        if config.peripherals.is_none() {
+            let mut peripheral: PeripheralConfig = try!(serde_json::from_str(text));
+            peripheral.name = String::new();
+            config.peripherals = Some(vec![peripheral]);
+        }
+        Ok(config)
+    }
+
+    // All configured peripherals.
+    pub fn peripherals(&self) -> Vec<PeripheralConfig> {
+        self.peripherals.clone().unwrap_or(Vec::new())
+    }
+}
+
+// Command sent to the daemon over the control socket
+#[derive(Serialize, Deserialize)]
+pub struct ControlRequest {
+    pub args: Vec<String>,
+}
+
+// Output and exit status of a command run by the daemon
+#[derive(Serialize, Deserialize)]
+pub struct ControlResponse {
+    pub status: i32,
+    pub output: String,
+}
+
+// Output of a command with --json. Either value or error is set.
+#[derive(Serialize)]
+pub struct CommandOutput<T> {
+    pub command: String,
+    pub ok: bool,
+    pub value: Option<T>,
+    pub error: Option<String>,
+    pub status: i32,
+}
+
+// Value of the color command with --json
+#[derive(Serialize)]
+pub struct ColorOutput {
+    pub raw: String,
+    pub color: Color,
+}
+
+// Value of the status command with --json
+#[derive(Serialize)]
+pub struct StatusOutput {
+    pub uptime: i64,
+    pub connected: bool,
+    pub peripherals: Vec<PeripheralStatus>,
+}
+
+#[derive(Serialize)]
+pub struct PeripheralStatus {
+    pub name: String,
+    pub temp: Option<f64>,
+    pub time: Option<i64>,
+    pub transactions: u64,
+    pub retries: u64,
+    pub errors: u64,
+    pub resyncs: u64,
+}
+
+// Reading of the watch command with --json
+#[derive(Serialize)]
+pub struct WatchOutput {
+    pub time: String,
+    pub value: f64,
+    pub text: String,
+    pub changed: bool,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub mean: Option<f64>,
+}
+
+// Report of the diagnose command
+#[derive(Serialize)]
+pub struct DiagnoseOutput {
+    pub passed: bool,
+    pub checks: Vec<DiagnoseCheck>,
+}
+
+#[derive(Serialize)]
+pub struct DiagnoseCheck {
+    pub name: String,
+    pub ok: bool,
+    pub detail: String,
+}
+
+// Result of the bench command for one clock speed and byte delay
+#[derive(Serialize)]
+pub struct BenchRun {
+    pub speed_hz: Option<u32>,
+    pub byte_delay_us: u64,
+    pub transactions: u32,
+    pub per_second: f64,
+    pub latency_p50_ms: f64,
+    pub latency_p90_ms: f64,
+    pub latency_p99_ms: f64,
+    pub latency_max_ms: f64,
+    pub crc_errors: u64,
+    pub framing_errors: u64,
+    pub failures: u32,
+    pub error_rate: f64,
+}
+
+// Coefficients calculated by the calibrate command
+#[derive(Serialize)]
+pub struct CalibrateOutput {
+    pub temp_sh_a: f64,
+    pub temp_sh_b: f64,
+    pub temp_sh_c: f64,
 }
 
 // Send color to server
@@ -76,6 +231,15 @@ impl Color {
         Color{..Default::default()}
     }
 
+    pub fn rgb(red: f32, green: f32, blue: f32) -> Self {
+        Color{mode: "rgb".to_string(), red: red, green: green, blue: blue, ..Default::default()}
+    }
+
+    pub fn hsv(hue: f32, saturation: f32, value: f32) -> Self {
+        Color{mode: "hsv".to_string(), hue: hue, saturation: saturation, value: value,
+              ..Default::default()}
+    }
+
     pub fn from_raw(value: u32) -> Self {
         let mut color = Color::new();
         color.update(value);
@@ -163,3 +327,4 @@ impl Color {
         raw
     }
 }
+
@@ -1,18 +1,26 @@
 
-use std::io::prelude::*;
-use std::{io, thread, time};
-
-use spidev::{Spidev, SpidevTransfer};
+use std::{fmt, thread, time};
+use std::io::{self, Write};
 
 use crc8::Crc8;
 
+use capabilities::*;
+use error::DomoError;
+use lock::BusLock;
+use transport::Transport;
+
 
-const TYPE_GETTER2: u8 = 0b00000000;
-const TYPE_GETTER4: u8 = 0b01000000;
-const TYPE_SETTER2: u8 = 0b10000000;
-const TYPE_SETTER4: u8 = 0b11000000;
+pub const TYPE_GETTER2: u8 = 0b00000000;
+pub const TYPE_GETTER4: u8 = 0b01000000;
+pub const TYPE_SETTER2: u8 = 0b10000000;
+pub const TYPE_SETTER4: u8 = 0b11000000;
+pub const TYPE_MASK: u8 = 0b11000000;
 
+pub const CMD_PROTOCOL_VERSION: u8 = 0x01; // protocol version of the firmware
+pub const CMD_REGISTER_INDEX: u8 = 0x02; // select entry for CMD_REGISTER_INFO
+pub const CMD_REGISTER_INFO: u8 = 0x03; // register descriptor, see capabilities.rs
 pub const CMD_COLOR: u8 = 0x05;
+pub const CMD_EVENTS: u8 = 0x06; // pending events (EVENT_*), cleared when read
 pub const CMD_TEMP_NOW: u8 = 0x11; // current temp (calculated on AVR)
 pub const CMD_TEMP_AVG: u8 = 0x12; // average temp (calculated on AVR)
 pub const CMD_TEMP_RAW: u8 = 0x13; // raw temp sensor reading
@@ -20,43 +28,208 @@ pub const CMD_TEMP_RSUM: u8 = 0x14; // sum of 256 raw temp sensor readings
 pub const CMD_TEMP_SRES: u8 = 0x15; // constant: series resistor
 pub const CMD_TEMP_NRES: u8 = 0x16; // constant: NTC resistor at 25°C
 pub const CMD_TEMP_BCOE: u8 = 0x17; // constant: NTC β-coefficient
-pub const CMD_TEST: u8 = 0x20;
+pub const CMD_TEST: u8 = 0x20; // always reads TEST_VALUE
+pub const CMD_FIRMWARE_VERSION: u8 = 0x21; // firmware version string (extended)
+pub const CMD_BOOT_CONTROL: u8 = 0x30; // enter or leave the bootloader
+pub const CMD_BOOT_ADDRESS: u8 = 0x31; // flash address for CMD_BOOT_PAGE
+pub const CMD_BOOT_PAGE: u8 = 0x32; // flash page at CMD_BOOT_ADDRESS (extended)
+
+// Value of CMD_TEST.
+pub const TEST_VALUE: u32 = 0xabcd;
+
+// Bits in CMD_EVENTS.
+pub const EVENT_COLOR: u32 = 0x01; // color changed on the peripheral
+
+// Command ID reserved for extended frames, which carry a payload of any length
+// up to 255 bytes:
+//   getter: host sends [EXT_GETTER, cmd],
+//           peripheral replies [0xff, length, payload..., crc]
+//   setter: host sends [EXT_SETTER, cmd, length, payload..., crc]
+// The CRC is calculated over all preceding bytes except the 0xff start byte.
+pub const CMD_EXTENDED: u8 = 0x3f;
+pub const EXT_GETTER: u8 = TYPE_GETTER2 | CMD_EXTENDED;
+pub const EXT_SETTER: u8 = TYPE_SETTER2 | CMD_EXTENDED;
+
+// Give up resyncing when no start-of-command is seen within this many bytes.
+const RESYNC_MAX_BYTES: usize = 256;
+
+// One register access in a batch, see Peripheral::run_batch.
+#[derive(Clone, Copy, Debug)]
+pub enum BatchOp {
+    Get { cmd: u8, length: u8 },
+    Set { cmd: u8, length: u8, value: u32 },
+}
+
+// How failed transactions are retried.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    // Number of retries after the first attempt.
+    pub retries: u32,
+    // Delay before the first retry, doubled for every next retry.
+    pub backoff: time::Duration,
+    // Resync after this many framing or CRC errors in a row (0 = never).
+    pub resync_after: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 3,
+            backoff: time::Duration::from_millis(10),
+            resync_after: 2,
+        }
+    }
+}
+
+// Counters for diagnosing a bad link.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    pub transactions: u64,
+    pub retries: u64,
+    pub framing_errors: u64,
+    pub crc_errors: u64,
+    pub resyncs: u64,
+    pub failures: u64,
+}
+
+impl Stats {
+    pub fn errors(&self) -> u64 {
+        self.framing_errors + self.crc_errors + self.failures
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{} transactions, {} retries, {} framing errors, {} CRC errors, {} resyncs, {} \
+                failures",
+               self.transactions,
+               self.retries,
+               self.framing_errors,
+               self.crc_errors,
+               self.resyncs,
+               self.failures)
+    }
+}
 
-pub struct Peripheral {
-    spi: Spidev,
+pub struct Peripheral<T: Transport> {
+    transport: T,
     crc8: Crc8,
+    retry_policy: RetryPolicy,
+    verify_writes: bool,
+    byte_delay: time::Duration,
+    batch_transfers: bool,
+    bus_lock: Option<BusLock>,
+    stats: Stats,
+    // Number of framing/CRC errors since the last successful transaction.
+    bad_frames: u32,
 }
 
-impl Peripheral {
-    pub fn open(path: &str) -> Result<Peripheral, io::Error> {
-        Ok(Peripheral {
-            spi: try!(Spidev::open(path)),
+impl<T: Transport> Peripheral<T> {
+    pub fn new(transport: T) -> Peripheral<T> {
+        Peripheral {
+            transport: transport,
             crc8: Crc8::create_msb(0x07),
-        })
+            retry_policy: RetryPolicy::default(),
+            verify_writes: false,
+            byte_delay: time::Duration::from_millis(1),
+            batch_transfers: false,
+            bus_lock: None,
+            stats: Stats::default(),
+            bad_frames: 0,
+        }
+    }
+
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
+    }
+
+    // Read back every register after writing to it, to make sure the
+    // peripheral received the new value.
+    pub fn set_verify_writes(&mut self, verify_writes: bool) {
+        self.verify_writes = verify_writes;
+    }
+
+    // Time to wait before sending each byte, so the AVR can keep up. The
+    // default is 1ms.
+    pub fn set_byte_delay(&mut self, delay: time::Duration) {
+        self.byte_delay = delay;
+    }
+
+    pub fn byte_delay(&self) -> time::Duration {
+        self.byte_delay
+    }
+
+    // Send batches in a single transfer even when there is a byte delay. The
+    // delay isn't kept within such a transfer, so this only works when the
+    // AVR keeps up at the clock speed of the bus.
+    pub fn set_batch_transfers(&mut self, batch_transfers: bool) {
+        self.batch_transfers = batch_transfers;
+    }
+
+    // Hold this lock during every transaction, so other processes using the
+    // same bus don't mix their bytes with ours.
+    pub fn set_bus_lock(&mut self, lock: BusLock) {
+        self.bus_lock = Some(lock);
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    // Run a transaction while holding the bus lock (if any).
+    fn locked<R, F>(&mut self, transaction: F) -> Result<R, DomoError>
+        where F: FnOnce(&mut Self) -> Result<R, DomoError>
+    {
+        if let Some(ref mut lock) = self.bus_lock {
+            try!(lock.acquire());
+        }
+        let result = transaction(self);
+        if let Some(ref mut lock) = self.bus_lock {
+            lock.release();
+        }
+        result
+    }
+
+    pub fn resync(&mut self) -> Result<(), DomoError> {
+        self.locked(|p| p.resync_locked())
     }
 
-    pub fn resync(&mut self) -> Result<(), io::Error> {
+    fn resync_locked(&mut self) -> Result<(), DomoError> {
         let cmd = TYPE_GETTER2 | CMD_TEST;
-        try!(self.spi.write(&[cmd]));
+        try!(self.transport.discard_input());
+        try!(self.transport.write(&[cmd]));
 
         // read until start-of-command
-        loop {
-            thread::sleep(time::Duration::from_millis(1));
-            let mut transfer = SpidevTransfer::write(&[cmd]);
-            try!(self.spi.transfer(&mut transfer));
+        let mut found = false;
+        for _ in 0..RESYNC_MAX_BYTES {
+            thread::sleep(self.byte_delay);
+            let mut rx: [u8; 1] = [0; 1];
+            try!(self.transport.transfer(&[cmd], &mut rx));
             // start of command
-            if transfer.rx_buf.unwrap()[0] == 0xff {
+            if rx[0] == 0xff {
+                found = true;
                 break;
             }
         }
+        if !found {
+            return Err(DomoError::Protocol("no response from peripheral in resync".to_string()));
+        }
 
         // read rest of command
         let mut buf: [u8; 3] = [0; 3];
         for i in 0..3 as usize {
-            thread::sleep(time::Duration::from_millis(1));
-            try!(self.spi.read(&mut buf[i..i + 1]));
+            thread::sleep(self.byte_delay);
+            try!(self.transport.read(&mut buf[i..i + 1]));
         }
 
+        // responses to the extra commands sent above may still be underway
+        try!(self.transport.discard_input());
+
         // is this the correct response?
         if &buf[..] == [0xcd, 0xab, 0x1f] {
             Ok(())
@@ -65,46 +238,360 @@ impl Peripheral {
                                   buf[0],
                                   buf[1],
                                   buf[2]);
-            Err(io::Error::new(io::ErrorKind::InvalidData, err_str))
+            Err(DomoError::Protocol(err_str))
+        }
+    }
+
+    // Run a transaction, retrying it according to the retry policy. Framing and
+    // CRC errors may trigger a resync before the next attempt.
+    fn retry<R, F>(&mut self, transaction: F) -> Result<R, DomoError>
+        where F: FnMut(&mut Self) -> Result<R, DomoError>
+    {
+        self.locked(|p| p.retry_locked(transaction))
+    }
+
+    fn retry_locked<R, F>(&mut self, mut transaction: F) -> Result<R, DomoError>
+        where F: FnMut(&mut Self) -> Result<R, DomoError>
+    {
+        let mut backoff = self.retry_policy.backoff;
+        let mut attempt = 0;
+        loop {
+            self.stats.transactions += 1;
+            let err = match transaction(self) {
+                Ok(result) => {
+                    self.bad_frames = 0;
+                    return Ok(result);
+                }
+                Err(err @ DomoError::Argument(_)) => return Err(err),
+                Err(err) => err,
+            };
+            self.transport.note(&format!("error: {}", err));
+
+            if err.is_bad_frame() {
+                self.bad_frames += 1;
+                if self.retry_policy.resync_after > 0 &&
+                   self.bad_frames >= self.retry_policy.resync_after {
+                    self.bad_frames = 0;
+                    self.stats.resyncs += 1;
+                    if let Err(err) = self.resync() {
+                        let _ = writeln!(io::stderr(), "automatic resync failed: {}", err);
+                    }
+                }
+            }
+
+            if attempt >= self.retry_policy.retries {
+                self.stats.failures += 1;
+                return Err(err);
+            }
+            attempt += 1;
+            self.stats.retries += 1;
+            thread::sleep(backoff);
+            backoff = backoff * 2;
+        }
+    }
+
+    pub fn read_number(&mut self, cmd: u8, length: u8) -> Result<u32, DomoError> {
+        self.retry(|p| p.read_number_once(cmd, length))
+    }
+
+    pub fn write_number(&mut self, cmd: u8, length: u8, value: u32) -> Result<(), DomoError> {
+        if self.verify_writes {
+            self.retry(|p| p.write_number_verified(cmd, length, value))
+        } else {
+            self.retry(|p| p.write_number_once(cmd, length, value))
         }
     }
 
-    pub fn read_number(&mut self, cmd: u8, length: u8) -> Result<u32, io::Error> {
+    // Write a register without reading it back, even with verify_writes. For
+    // writes after which the AVR doesn't answer, like jumping to the
+    // bootloader.
+    pub fn write_number_unverified(&mut self,
+                                   cmd: u8,
+                                   length: u8,
+                                   value: u32)
+                                   -> Result<(), DomoError> {
+        self.retry(|p| p.write_number_once(cmd, length, value))
+    }
+
+    fn write_number_verified(&mut self, cmd: u8, length: u8, value: u32) -> Result<(), DomoError> {
+        try!(self.write_number_once(cmd, length, value));
+        let read = try!(self.read_number_once(cmd, length));
+        if read != value {
+            return Err(DomoError::WriteMismatch {
+                cmd: cmd,
+                written: value,
+                read: read,
+            });
+        }
+        Ok(())
+    }
+
+    // Run several register accesses in a single transfer, so the bus is busy
+    // for a much shorter time than with separate reads and writes. Only 2- and
+    // 4-byte registers can be batched. The result of every access is returned
+    // in order (the written value for setters). Accesses that fail are retried
+    // one by one, according to the retry policy.
+    // A transfer can't wait between bytes, so with a byte delay the accesses
+    // are done one by one unless batch transfers are enabled.
+    pub fn run_batch(&mut self, ops: &[BatchOp]) -> Result<Vec<Result<u32, DomoError>>, DomoError> {
+        self.locked(|p| p.run_batch_locked(ops))
+    }
+
+    fn run_batch_locked(&mut self,
+                        ops: &[BatchOp])
+                        -> Result<Vec<Result<u32, DomoError>>, DomoError> {
+        if !self.batch_transfers && self.byte_delay != time::Duration::from_millis(0) {
+            let mut results = Vec::new();
+            for op in ops {
+                results.push(self.run_op(op));
+            }
+            return Ok(results);
+        }
+
+        let mut requests = Vec::new();
+        for op in ops {
+            match *op {
+                BatchOp::Get { cmd, length } => {
+                    requests.push((vec![try!(getter_cmd(cmd, length))], length as usize + 2));
+                }
+                BatchOp::Set { cmd, length, value } => {
+                    let rawcmd = try!(setter_cmd(cmd, length));
+                    requests.push((self.encode_setter(rawcmd, length, value), 0));
+                    if self.verify_writes {
+                        requests.push((vec![try!(getter_cmd(cmd, length))], length as usize + 2));
+                    }
+                }
+            }
+        }
+
+        self.stats.transactions += ops.len() as u64;
+        let responses = try!(self.transport.batch(&requests));
+
+        let mut responses = responses.into_iter();
+        let mut results = Vec::new();
+        for op in ops {
+            let result = match *op {
+                BatchOp::Get { cmd, length } => {
+                    let response = responses.next().unwrap();
+                    self.decode_response(cmd, length, &response)
+                }
+                BatchOp::Set { cmd, length, value } => {
+                    responses.next(); // setters get no response
+                    if self.verify_writes {
+                        let response = responses.next().unwrap();
+                        match self.decode_response(cmd, length, &response) {
+                            Ok(read) if read != value => {
+                                Err(DomoError::WriteMismatch {
+                                    cmd: cmd,
+                                    written: value,
+                                    read: read,
+                                })
+                            }
+                            Ok(_) => Ok(value),
+                            Err(err) => Err(err),
+                        }
+                    } else {
+                        Ok(value)
+                    }
+                }
+            };
+            if let Err(ref err) = result {
+                self.transport.note(&format!("error: {}", err));
+            }
+            results.push(result);
+        }
+
+        // Retry failed accesses separately. After one bad frame the rest of the
+        // batch may be out of sync as well, the retry policy takes care of
+        // resyncing.
+        for (op, result) in ops.iter().zip(results.iter_mut()) {
+            if result.is_ok() {
+                continue;
+            }
+            self.stats.retries += 1;
+            *result = self.run_op(op);
+        }
+
+        Ok(results)
+    }
+
+    // Do a single access of a batch on its own, with retries.
+    fn run_op(&mut self, op: &BatchOp) -> Result<u32, DomoError> {
+        match *op {
+            BatchOp::Get { cmd, length } => self.read_number(cmd, length),
+            BatchOp::Set { cmd, length, value } => {
+                self.write_number(cmd, length, value).map(|_| value)
+            }
+        }
+    }
+
+    // Ask the firmware which protocol version and registers it supports.
+    pub fn discover(&mut self) -> Result<Capabilities, DomoError> {
+        self.locked(|p| p.discover_locked())
+    }
+
+    fn discover_locked(&mut self) -> Result<Capabilities, DomoError> {
+        // Don't retry: firmware from before discovery doesn't answer this.
+        let version = match self.read_number_once(CMD_PROTOCOL_VERSION, 2) {
+            Ok(version) => version as u16,
+            Err(DomoError::Framing { .. }) |
+            Err(DomoError::Crc { .. }) => return Ok(Capabilities::legacy()),
+            Err(err) => return Err(err),
+        };
+
+        let mut registers = Vec::new();
+        for index in 0..MAX_REGISTERS {
+            let mut results = try!(self.run_batch(&[BatchOp::Set {
+                                                        cmd: CMD_REGISTER_INDEX,
+                                                        length: 2,
+                                                        value: index,
+                                                    },
+                                                    BatchOp::Get {
+                                                        cmd: CMD_REGISTER_INFO,
+                                                        length: 4,
+                                                    }]));
+            let info = try!(results.pop().unwrap());
+            try!(results.pop().unwrap());
+            match RegisterInfo::decode(info) {
+                Some(info) => registers.push(info),
+                None => break,
+            }
+        }
+
+        Ok(Capabilities {
+            version: version,
+            registers: registers,
+        })
+    }
+
+    // Read a variable-length register using an extended frame.
+    pub fn read_bytes(&mut self, cmd: u8) -> Result<Vec<u8>, DomoError> {
+        self.retry(|p| p.read_bytes_once(cmd))
+    }
+
+    // Write a variable-length register using an extended frame.
+    pub fn write_bytes(&mut self, cmd: u8, payload: &[u8]) -> Result<(), DomoError> {
+        self.retry(|p| p.write_bytes_once(cmd, payload))
+    }
+
+    pub fn read_string(&mut self, cmd: u8) -> Result<String, DomoError> {
+        let bytes = try!(self.read_bytes(cmd));
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    pub fn read_u64(&mut self, cmd: u8) -> Result<u64, DomoError> {
+        let bytes = try!(self.read_bytes(cmd));
+        bytes_to_number(cmd, &bytes, 8)
+    }
+
+    pub fn write_u64(&mut self, cmd: u8, value: u64) -> Result<(), DomoError> {
+        self.write_bytes(cmd, &number_to_bytes(value, 8))
+    }
+
+    // Read a register of 1 to 4 or 8 bytes. Other widths than 2 and 4 use an
+    // extended frame.
+    pub fn read_register(&mut self, cmd: u8, width: u8) -> Result<u64, DomoError> {
+        match width {
+            8 => self.read_u64(cmd),
+            _ => self.read_number(cmd, width).map(|value| value as u64),
+        }
+    }
+
+    // Write a register of 1 to 4 or 8 bytes, see read_register.
+    pub fn write_register(&mut self, cmd: u8, width: u8, value: u64) -> Result<(), DomoError> {
+        match width {
+            8 => self.write_u64(cmd, value),
+            _ => self.write_number(cmd, width, value as u32),
+        }
+    }
+
+    fn read_number_once(&mut self, cmd: u8, length: u8) -> Result<u32, DomoError> {
         let rawcmd = match length {
             2 => cmd | TYPE_GETTER2,
             4 => cmd | TYPE_GETTER4,
-            _ => panic!("length is not 2 or 4 in read_number"),
+            1 | 3 => {
+                let bytes = try!(self.read_bytes_once(cmd));
+                return bytes_to_number(cmd, &bytes, length).map(|value| value as u32);
+            }
+            _ => return Err(DomoError::Argument(format!("cannot read {}-byte number", length))),
         };
 
-        thread::sleep(time::Duration::from_millis(1));
-        try!(self.spi.write(&[rawcmd]));
+        thread::sleep(self.byte_delay);
+        try!(self.transport.write(&[rawcmd]));
 
         let mut buf: [u8; 1] = [0; 1];
-        thread::sleep(time::Duration::from_millis(1));
-        try!(self.spi.read(&mut buf));
+        thread::sleep(self.byte_delay);
+        try!(self.transport.read(&mut buf));
         if buf[0] != 0xff {
-            let err_string = format!("expected 0xff from SPI, got {}", buf[0]);
-            return Err(io::Error::new(io::ErrorKind::InvalidData, err_string));
+            self.stats.framing_errors += 1;
+            return Err(DomoError::Framing {
+                expected: 0xff,
+                got: buf[0],
+            });
         }
 
         let mut buf: [u8; 6] = [0; 6];
         buf[0] = rawcmd;
         for i in 0..length as usize + 1 {
-            thread::sleep(time::Duration::from_millis(1));
-            try!(self.spi.read(&mut buf[i + 1..i + 2]));
+            thread::sleep(self.byte_delay);
+            try!(self.transport.read(&mut buf[i + 1..i + 2]));
         }
 
+        self.decode_getter(&buf, length)
+    }
+
+    fn write_number_once(&mut self, cmd: u8, length: u8, value: u32) -> Result<(), DomoError> {
+        let rawcmd = match length {
+            2 => cmd | TYPE_SETTER2,
+            4 => cmd | TYPE_SETTER4,
+            1 | 3 => return self.write_bytes_once(cmd, &number_to_bytes(value as u64, length)),
+            _ => return Err(DomoError::Argument(format!("cannot write {}-byte number", length))),
+        };
+
+        let buf = self.encode_setter(rawcmd, length, value);
+        for i in 0..buf.len() {
+            thread::sleep(self.byte_delay);
+            try!(self.transport.write(&buf[i..i + 1]));
+        }
+        self.transport.note(&format!("set {:02x} ({} bytes)", cmd, length));
+
+        Ok(())
+    }
+
+    // Check a getter response as received in a batch: the start byte, the
+    // payload and the CRC.
+    fn decode_response(&mut self, cmd: u8, length: u8, response: &[u8]) -> Result<u32, DomoError> {
+        if response[0] != 0xff {
+            self.stats.framing_errors += 1;
+            return Err(DomoError::Framing {
+                expected: 0xff,
+                got: response[0],
+            });
+        }
+        let mut buf = vec![try!(getter_cmd(cmd, length))];
+        buf.extend_from_slice(&response[1..]);
+        self.decode_getter(&buf, length)
+    }
+
+    // Check and decode the response to a getter. `buf` holds the command byte,
+    // the payload and the CRC.
+    fn decode_getter(&mut self, buf: &[u8], length: u8) -> Result<u32, DomoError> {
         let crc = buf[length as usize + 1];
         let crc2 = self.crc8.calc(&buf, length as i32 + 1, 0);
         if crc != crc2 {
-            print!("checksum problem (received {:02x}, calculated {:02x}) for message",
-                   crc,
-                   crc2);
-            for c in &buf[0 + 1..length as usize + 1] {
-                print!(" {:02x}", c);
+            self.stats.crc_errors += 1;
+            let mut msg = format!("checksum problem (received {:02x}, calculated {:02x}) for \
+                                   message",
+                                  crc,
+                                  crc2);
+            for c in &buf[1..length as usize + 1] {
+                msg.push_str(&format!(" {:02x}", c));
             }
-            println!("");
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "CRC check failed"));
+            let _ = writeln!(io::stderr(), "{}", msg);
+            return Err(DomoError::Crc {
+                received: crc,
+                calculated: crc2,
+            });
         }
 
         let mut result: u32 = 0;
@@ -114,16 +601,12 @@ impl Peripheral {
             result += c;
         }
 
+        self.transport.note(&format!("get {:02x} ({} bytes): CRC ok", buf[0] & !TYPE_MASK, length));
         Ok(result)
     }
 
-    pub fn write_number(&mut self, cmd: u8, length: u8, value: u32) -> Result<(), io::Error> {
-        let rawcmd = match length {
-            2 => cmd | TYPE_SETTER2,
-            4 => cmd | TYPE_SETTER4,
-            _ => panic!("length is not 2 or 4 in write_number"),
-        };
-
+    // Build a setter frame: command byte, payload and CRC.
+    fn encode_setter(&mut self, rawcmd: u8, length: u8, value: u32) -> Vec<u8> {
         let mut buf: [u8; 6] = [0; 6];
         buf[0] = rawcmd;
         let mut value2 = value;
@@ -131,14 +614,110 @@ impl Peripheral {
             buf[i + 1] = (value2 % 256) as u8;
             value2 /= 256;
         }
-        let crc = Crc8::create_msb(0x07).calc(&buf, length as i32 + 1, 0);
+        let crc = self.crc8.calc(&buf, length as i32 + 1, 0);
         buf[length as usize + 1] = crc;
+        buf[..length as usize + 2].to_vec()
+    }
+
+    fn read_byte(&mut self) -> Result<u8, DomoError> {
+        let mut buf: [u8; 1] = [0; 1];
+        thread::sleep(self.byte_delay);
+        try!(self.transport.read(&mut buf));
+        Ok(buf[0])
+    }
+
+    fn write_byte(&mut self, c: u8) -> Result<(), DomoError> {
+        thread::sleep(self.byte_delay);
+        try!(self.transport.write(&[c]));
+        Ok(())
+    }
+
+    fn read_bytes_once(&mut self, cmd: u8) -> Result<Vec<u8>, DomoError> {
+        try!(self.write_byte(EXT_GETTER));
+        try!(self.write_byte(cmd));
+
+        let start = try!(self.read_byte());
+        if start != 0xff {
+            self.stats.framing_errors += 1;
+            return Err(DomoError::Framing {
+                expected: 0xff,
+                got: start,
+            });
+        }
+
+        let length = try!(self.read_byte());
+        let mut buf = vec![EXT_GETTER, cmd, length];
+        for _ in 0..length as usize + 1 {
+            buf.push(try!(self.read_byte()));
+        }
+
+        let crc = buf.pop().unwrap();
+        let crc2 = self.crc8.calc(&buf, buf.len() as i32, 0);
+        if crc != crc2 {
+            self.stats.crc_errors += 1;
+            return Err(DomoError::Crc {
+                received: crc,
+                calculated: crc2,
+            });
+        }
+
+        self.transport.note(&format!("get {:02x} (extended, {} bytes): CRC ok", cmd, length));
+        Ok(buf.split_off(3))
+    }
 
-        for i in 0..length as usize + 2 {
-            thread::sleep(time::Duration::from_millis(1));
-            try!(self.spi.write(&buf[i..i + 1]));
+    fn write_bytes_once(&mut self, cmd: u8, payload: &[u8]) -> Result<(), DomoError> {
+        if payload.len() > 255 {
+            return Err(DomoError::Argument(format!("payload of {} bytes is too long",
+                                                   payload.len())));
         }
 
+        let mut buf = vec![EXT_SETTER, cmd, payload.len() as u8];
+        buf.extend_from_slice(payload);
+        let crc = self.crc8.calc(&buf, buf.len() as i32, 0);
+        buf.push(crc);
+
+        for c in buf {
+            try!(self.write_byte(c));
+        }
+
+        self.transport.note(&format!("set {:02x} (extended, {} bytes)", cmd, payload.len()));
         Ok(())
     }
 }
+
+// Command byte of a compact getter.
+fn getter_cmd(cmd: u8, length: u8) -> Result<u8, DomoError> {
+    match length {
+        2 => Ok(cmd | TYPE_GETTER2),
+        4 => Ok(cmd | TYPE_GETTER4),
+        _ => Err(DomoError::Argument(format!("cannot batch {}-byte register", length))),
+    }
+}
+
+// Command byte of a compact setter.
+fn setter_cmd(cmd: u8, length: u8) -> Result<u8, DomoError> {
+    match length {
+        2 => Ok(cmd | TYPE_SETTER2),
+        4 => Ok(cmd | TYPE_SETTER4),
+        _ => Err(DomoError::Argument(format!("cannot batch {}-byte register", length))),
+    }
+}
+
+// Decode a little-endian number of `length` bytes.
+fn bytes_to_number(cmd: u8, bytes: &[u8], length: u8) -> Result<u64, DomoError> {
+    if bytes.len() != length as usize {
+        return Err(DomoError::Protocol(format!("expected {} bytes from register {:02x}, got {}",
+                                               length,
+                                               cmd,
+                                               bytes.len())));
+    }
+    let mut result: u64 = 0;
+    for (i, c) in bytes.iter().enumerate() {
+        result |= (*c as u64) << (i * 8);
+    }
+    Ok(result)
+}
+
+fn number_to_bytes(value: u64, length: u8) -> Vec<u8> {
+    (0..length as usize).map(|i| (value >> (i * 8)) as u8).collect()
+}
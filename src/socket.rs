@@ -1,5 +1,5 @@
 
-use std::{cmp, process, thread, time};
+use std::{cmp, thread, time};
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{Sender, Receiver};
 
@@ -7,6 +7,7 @@ use serde_json;
 use ws;
 
 use chrono::*;
+use error::DomoError;
 use messages::*;
 
 pub struct Socket {
@@ -15,6 +16,7 @@ pub struct Socket {
     rx_msg_to_server: Arc<Mutex<Receiver<String>>>,
     tx_msg_from_server: Sender<MsgServer>,
     verified_time: Arc<Mutex<bool>>,
+    connected: Arc<Mutex<bool>>,
 }
 
 impl Socket {
@@ -22,13 +24,15 @@ impl Socket {
                    name: String,
                    serial: String,
                    rx_msg_to_server: Receiver<String>,
-                   tx_msg_from_server: Sender<MsgServer>) {
+                   tx_msg_from_server: Sender<MsgServer>,
+                   connected: Arc<Mutex<bool>>) {
         let socket = Socket {
             name: name,
             serial: serial,
             rx_msg_to_server: Arc::new(Mutex::new(rx_msg_to_server)),
             tx_msg_from_server: tx_msg_from_server,
             verified_time: Arc::new(Mutex::new(false)),
+            connected: connected,
         };
 
         socket.run(url);
@@ -39,7 +43,10 @@ impl Socket {
         loop {
             match ws::connect(url, |out| {
                 delay_seconds = 1;
-                self.send_hello(&out);
+                *self.connected.lock().unwrap() = true;
+                if let Err(err) = self.send_hello(&out) {
+                    println!("failed to send connect message: {}", err);
+                }
 
                 // Start thread that sends messages received via `rx_msg_to_server`
                 let verified_time = self.verified_time.clone();
@@ -76,12 +83,13 @@ impl Socket {
                              err);
                 }
             };
+            *self.connected.lock().unwrap() = false;
             thread::sleep(time::Duration::from_secs(delay_seconds));
             println!("Reconnecting...");
         }
     }
 
-    fn send_hello(&self, out: &ws::Sender) {
+    fn send_hello(&self, out: &ws::Sender) -> Result<(), ws::Error> {
         // send 'connect' message
         let msg_connect = MsgConnect {
             message: "connect".to_string(),
@@ -89,30 +97,27 @@ impl Socket {
             serial: self.serial.clone(),
         };
         let msg_connect_encoded = serde_json::to_string(&msg_connect).unwrap();
-        match out.send(msg_connect_encoded) {
-            Ok(_) => {}
-            Err(err) => {
-                println!("failed to send message: {}", err);
-                process::exit(1);
-            }
-        };
+        out.send(msg_connect_encoded)
     }
 
     fn on_message(&self, msg_encoded: ws::Message) -> Result<(), ws::Error> {
+        if let Err(err) = self.handle_message(msg_encoded) {
+            println!("WARNING: {}", err);
+        }
+        Ok(())
+    }
+
+    fn handle_message(&self, msg_encoded: ws::Message) -> Result<(), DomoError> {
         let msg_text = match msg_encoded {
             ws::Message::Text(val) => val,
             ws::Message::Binary(_) => {
-                println!("received binary message");
-                process::exit(1);
+                return Err(DomoError::ServerMessage("received binary message".to_string()));
             }
         };
         let msg: MsgServer = match serde_json::from_str(&msg_text.as_str()) {
             Ok(msg) => msg,
             Err(err) => {
-                println!("got invalid message from server: {}\nmessage: {}",
-                         err,
-                         &msg_text);
-                return Ok(());
+                return Err(DomoError::ServerMessage(format!("{}\nmessage: {}", err, &msg_text)));
             }
         };
 
@@ -125,10 +130,11 @@ impl Socket {
                     *verified_time = true;
                 }
                 Some(_) => {
-                    println!("WARNING: time not in sync");
+                    return Err(DomoError::ServerMessage("time not in sync".to_string()));
                 }
                 None => {
-                    println!("WARNING: no timestamp sent in time message");
+                    return Err(DomoError::ServerMessage("no timestamp sent in time message"
+                        .to_string()));
                 }
             };
         } else {
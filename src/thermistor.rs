@@ -0,0 +1,66 @@
+// Conversion from the resistance of an NTC thermistor to a temperature.
+// Source: https://learn.adafruit.com/thermistor/using-a-thermistor
+
+const KELVIN: f64 = 273.15; // 0°C
+const T0: f64 = 298.15; // nominal temperature of the β-model (25°C)
+
+// Resistance of the NTC when it is the lower half of a voltage divider with
+// the series resistor, given the ADC reading as a fraction (0..1).
+pub fn resistance(fvalue: f64, series_resistor: f64) -> f64 {
+    series_resistor / (1.0 / fvalue - 1.0)
+}
+
+// Simplified Steinhart–Hart equation using the β coefficient and the nominal
+// resistance at 25°C.
+pub fn beta_to_celsius(r: f64, r0: f64, b_coefficient: f64) -> f64 {
+    let tinv = (1.0 / T0) + 1.0 / b_coefficient * (r / r0).ln();
+    1.0 / tinv - KELVIN
+}
+
+// Full Steinhart–Hart equation: 1/T = A + B ln(R) + C ln(R)³
+pub fn steinhart_hart_to_celsius(r: f64, coefficients: (f64, f64, f64)) -> f64 {
+    let (a, b, c) = coefficients;
+    let ln_r = r.ln();
+    1.0 / (a + b * ln_r + c * ln_r.powi(3)) - KELVIN
+}
+
+// Calculate the Steinhart–Hart coefficients (A, B, C) from three measured
+// (temperature in °C, resistance) points.
+pub fn fit_steinhart_hart(points: &[(f64, f64); 3]) -> Result<(f64, f64, f64), String> {
+    if points.iter().any(|&(_, r)| !(r > 0.0) || !r.is_finite()) {
+        return Err("resistance out of range".to_string());
+    }
+    let l: Vec<f64> = points.iter().map(|&(_, r)| r.ln()).collect();
+    let y: Vec<f64> = points.iter().map(|&(t, _)| 1.0 / (t + KELVIN)).collect();
+    if l[0] == l[1] || l[0] == l[2] || l[1] == l[2] {
+        return Err("two points have the same resistance".to_string());
+    }
+
+    let gamma2 = (y[1] - y[0]) / (l[1] - l[0]);
+    let gamma3 = (y[2] - y[0]) / (l[2] - l[0]);
+    let c = (gamma3 - gamma2) / (l[2] - l[1]) / (l[0] + l[1] + l[2]);
+    let b = gamma2 - c * (l[0] * l[0] + l[0] * l[1] + l[1] * l[1]);
+    let a = y[0] - (b + l[0] * l[0] * c) * l[0];
+    if !(a.is_finite() && b.is_finite() && c.is_finite()) {
+        return Err("points don't give valid coefficients".to_string());
+    }
+    Ok((a, b, c))
+}
+
+#[test]
+fn test_steinhart_hart() {
+    // Coefficients of a common 10kΩ NTC.
+    let coefficients = (1.125308852e-3, 2.347125395e-4, 0.85663e-7);
+    let points = [(0.0, 32650.0), (25.0, 10000.0), (50.0, 3603.0)];
+    for &(t, r) in points.iter() {
+        assert!((steinhart_hart_to_celsius(r, coefficients) - t).abs() < 0.5);
+    }
+
+    let (a, b, c) = fit_steinhart_hart(&points).unwrap();
+    for &(t, r) in points.iter() {
+        assert!((steinhart_hart_to_celsius(r, (a, b, c)) - t).abs() < 1e-6);
+    }
+    assert!((beta_to_celsius(10000.0, 10000.0, 3950.0) - 25.0).abs() < 1e-9);
+
+    assert!(fit_steinhart_hart(&[(0.0, 32650.0), (25.0, 10000.0), (50.0, 10000.0)]).is_err());
+}
@@ -0,0 +1,271 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::time;
+
+use chrono::Local;
+
+use transport::Transport;
+
+
+// A trace is a text file with one line per exchange on the bus:
+//
+//     0.001062 > 52
+//     0.002131 < ff
+//     0.004250 < cd ab 1f
+//     0.004262 # get 12 (2 bytes): CRC ok
+//
+// The first column is the time in seconds since the start of the trace, the
+// second tells what happened: bytes sent (>), bytes received (<), a full
+// duplex transfer (<>, sent and received bytes separated by a slash), input
+// that was discarded (-) or a note from Peripheral about the decoded frame (#).
+
+// Records everything going over the wrapped transport into a trace file.
+pub struct TraceTransport<T: Transport> {
+    transport: T,
+    file: File,
+    start: time::Instant,
+}
+
+impl<T: Transport> TraceTransport<T> {
+    pub fn create(transport: T, path: &str) -> Result<TraceTransport<T>, io::Error> {
+        let mut file = try!(File::create(path));
+        try!(writeln!(file, "# domoc trace started at {}", Local::now().to_rfc3339()));
+        Ok(TraceTransport {
+            transport: transport,
+            file: file,
+            start: time::Instant::now(),
+        })
+    }
+
+    fn record(&mut self, event: &str, data: &str) {
+        let elapsed = self.start.elapsed();
+        let result = writeln!(self.file,
+                              "{}.{:06} {}{}{}",
+                              elapsed.as_secs(),
+                              elapsed.subsec_nanos() / 1000,
+                              event,
+                              if data.is_empty() { "" } else { " " },
+                              data);
+        if let Err(err) = result {
+            let _ = writeln!(io::stderr(), "WARNING: could not write trace: {}", err);
+        }
+    }
+}
+
+impl<T: Transport> Transport for TraceTransport<T> {
+    fn write(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        try!(self.transport.write(buf));
+        self.record(">", &to_hex(buf));
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), io::Error> {
+        try!(self.transport.read(buf));
+        self.record("<", &to_hex(buf));
+        Ok(())
+    }
+
+    fn transfer(&mut self, tx: &[u8], rx: &mut [u8]) -> Result<(), io::Error> {
+        try!(self.transport.transfer(tx, rx));
+        self.record("<>", &format!("{} / {}", to_hex(tx), to_hex(rx)));
+        Ok(())
+    }
+
+    fn discard_input(&mut self) -> Result<(), io::Error> {
+        try!(self.transport.discard_input());
+        self.record("-", "");
+        Ok(())
+    }
+
+    fn batch(&mut self, requests: &[(Vec<u8>, usize)]) -> Result<Vec<Vec<u8>>, io::Error> {
+        let responses = try!(self.transport.batch(requests));
+        for (&(ref request, _), response) in requests.iter().zip(responses.iter()) {
+            self.record(">", &to_hex(request));
+            if !response.is_empty() {
+                self.record("<", &to_hex(response));
+            }
+        }
+        Ok(responses)
+    }
+
+    fn note(&mut self, msg: &str) {
+        self.record("#", msg);
+    }
+
+    fn set_speed(&mut self, speed_hz: u32) -> Result<u32, io::Error> {
+        let previous = try!(self.transport.set_speed(speed_hz));
+        self.record("#", &format!("speed {} Hz", speed_hz));
+        Ok(previous)
+    }
+}
+
+// One line of a trace file, without the timestamp.
+#[derive(Debug)]
+enum Event {
+    Write(Vec<u8>),
+    Read(Vec<u8>),
+    Transfer(Vec<u8>, Vec<u8>),
+    Discard,
+}
+
+// Fake peripheral that answers with the bytes from a recorded trace. The host
+// must send the same bytes as when the trace was recorded, so a trace can be
+// replayed by running the same command.
+pub struct ReplayTransport {
+    events: Vec<Event>,
+    position: usize,
+}
+
+impl ReplayTransport {
+    pub fn open(path: &str) -> Result<ReplayTransport, io::Error> {
+        let file = try!(File::open(path));
+        let mut events = Vec::new();
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = try!(line);
+            match parse_line(&line) {
+                Ok(Some(event)) => events.push(event),
+                Ok(None) => {}
+                Err(msg) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("{}:{}: {}", path, i + 1, msg)))
+                }
+            }
+        }
+        Ok(ReplayTransport {
+            events: events,
+            position: 0,
+        })
+    }
+
+    fn next_event(&mut self) -> Result<&Event, io::Error> {
+        if self.position >= self.events.len() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "end of trace"));
+        }
+        self.position += 1;
+        Ok(&self.events[self.position - 1])
+    }
+}
+
+fn diverged(event: &Event, what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("replay diverges from trace: {}, trace has {:?}", what, event))
+}
+
+impl Transport for ReplayTransport {
+    fn write(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        match try!(self.next_event()) {
+            &Event::Write(ref data) if &data[..] == buf => Ok(()),
+            event => Err(diverged(event, &format!("write {}", to_hex(buf)))),
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), io::Error> {
+        match try!(self.next_event()) {
+            &Event::Read(ref data) if data.len() == buf.len() => {
+                buf.copy_from_slice(data);
+                Ok(())
+            }
+            event => Err(diverged(event, &format!("read {} bytes", buf.len()))),
+        }
+    }
+
+    fn transfer(&mut self, tx: &[u8], rx: &mut [u8]) -> Result<(), io::Error> {
+        match try!(self.next_event()) {
+            &Event::Transfer(ref data_tx, ref data_rx) if &data_tx[..] == tx => {
+                rx.copy_from_slice(data_rx);
+                Ok(())
+            }
+            event => Err(diverged(event, &format!("transfer {}", to_hex(tx)))),
+        }
+    }
+
+    fn discard_input(&mut self) -> Result<(), io::Error> {
+        match try!(self.next_event()) {
+            &Event::Discard => Ok(()),
+            event => Err(diverged(event, "discard input")),
+        }
+    }
+}
+
+fn to_hex(buf: &[u8]) -> String {
+    buf.iter().map(|c| format!("{:02x}", c)).collect::<Vec<_>>().join(" ")
+}
+
+fn from_hex(text: &str) -> Result<Vec<u8>, String> {
+    text.split_whitespace()
+        .map(|c| u8::from_str_radix(c, 16).map_err(|_| format!("invalid byte: {}", c)))
+        .collect()
+}
+
+// Parse a line of a trace file. Comments and notes are skipped (None).
+fn parse_line(line: &str) -> Result<Option<Event>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let mut parts = line.splitn(3, ' ');
+    parts.next(); // timestamp
+    let kind = parts.next().unwrap_or("");
+    let data = parts.next().unwrap_or("");
+    match kind {
+        ">" => Ok(Some(Event::Write(try!(from_hex(data))))),
+        "<" => Ok(Some(Event::Read(try!(from_hex(data))))),
+        "<>" => {
+            let mut halves = data.splitn(2, '/');
+            let tx = try!(from_hex(halves.next().unwrap_or("")));
+            let rx = try!(from_hex(halves.next().unwrap_or("")));
+            if tx.len() != rx.len() {
+                return Err("transfer with different lengths".to_string());
+            }
+            Ok(Some(Event::Transfer(tx, rx)))
+        }
+        "-" => Ok(Some(Event::Discard)),
+        "#" => Ok(None),
+        _ => Err(format!("unknown event: {}", kind)),
+    }
+}
+
+#[test]
+fn test_trace_replay() {
+    use std::env;
+    use emulator::Emulator;
+    use peripheral::*;
+
+    let path = env::temp_dir().join("domoc-test-trace.txt");
+    let path = path.to_str().unwrap();
+
+    let mut peripheral = Peripheral::new(TraceTransport::create(Emulator::new(), path).unwrap());
+    peripheral.set_batch_transfers(true);
+    peripheral.resync().unwrap();
+    peripheral.transport_mut().transport.inject_crc_error();
+    assert_eq!(peripheral.read_number(CMD_TEST, 2).unwrap(), 0xabcd);
+    peripheral.write_number(CMD_COLOR, 4, 0x4148ffff).unwrap();
+    assert_eq!(peripheral.read_number(CMD_COLOR, 4).unwrap(), 0x4148ffff);
+    let ops = [BatchOp::Set {
+                   cmd: CMD_COLOR,
+                   length: 4,
+                   value: 0x0100ffff,
+               },
+               BatchOp::Get {
+                   cmd: CMD_COLOR,
+                   length: 4,
+               }];
+    peripheral.run_batch(&ops).unwrap();
+    let capabilities = peripheral.discover().unwrap();
+    drop(peripheral);
+
+    let mut peripheral = Peripheral::new(ReplayTransport::open(path).unwrap());
+    peripheral.set_batch_transfers(true);
+    peripheral.resync().unwrap();
+    assert_eq!(peripheral.read_number(CMD_TEST, 2).unwrap(), 0xabcd);
+    assert_eq!(peripheral.stats().crc_errors, 1);
+    peripheral.write_number(CMD_COLOR, 4, 0x4148ffff).unwrap();
+    assert_eq!(peripheral.read_number(CMD_COLOR, 4).unwrap(), 0x4148ffff);
+    let results = peripheral.run_batch(&ops).unwrap();
+    assert_eq!(results[1].as_ref().unwrap(), &0x0100ffff);
+    let replayed = peripheral.discover().unwrap();
+    assert_eq!(replayed.registers.len(), capabilities.registers.len());
+    assert!(peripheral.read_number(CMD_TEST, 2).is_err());
+}
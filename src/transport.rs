@@ -0,0 +1,159 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::io::AsRawFd;
+
+use spidev::{spidevioctl, Spidev, SpidevOptions, SpidevTransfer};
+
+
+// Byte-level access to the bus the AVR is connected to. The framing of
+// commands (start byte, CRC) is done in Peripheral, a transport only moves
+// bytes.
+pub trait Transport {
+    // Send all bytes in `buf`, ignoring anything received at the same time.
+    fn write(&mut self, buf: &[u8]) -> Result<(), io::Error>;
+
+    // Fill `buf` with bytes received from the peripheral.
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), io::Error>;
+
+    // Send `tx` while receiving into `rx` (full duplex). Both buffers must
+    // have the same length.
+    fn transfer(&mut self, tx: &[u8], rx: &mut [u8]) -> Result<(), io::Error>;
+
+    // Drop bytes that were received but not yet read. Only buffered
+    // transports (like a UART) need to do something here.
+    fn discard_input(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    // Run several requests in one go. For every (request, response length)
+    // pair the request is sent, after which the given number of bytes is read.
+    // Transports that can queue transfers do this without pauses in between.
+    fn batch(&mut self, requests: &[(Vec<u8>, usize)]) -> Result<Vec<Vec<u8>>, io::Error> {
+        let mut responses = Vec::new();
+        for &(ref request, response_length) in requests {
+            try!(self.write(request));
+            let mut response = vec![0; response_length];
+            // Nothing is read for setters, so replaying a trace doesn't
+            // expect a read that was never recorded.
+            if response_length > 0 {
+                try!(self.read(&mut response));
+            }
+            responses.push(response);
+        }
+        Ok(responses)
+    }
+
+    // Describe what was just exchanged (the decoded frame or an error), for
+    // transports that record the traffic.
+    fn note(&mut self, _msg: &str) {}
+
+    // Change the clock speed of the bus. Returns the previous speed, so it can
+    // be restored.
+    fn set_speed(&mut self, _speed_hz: u32) -> Result<u32, io::Error> {
+        Err(io::Error::new(io::ErrorKind::InvalidInput,
+                           "the clock speed of this transport can't be changed"))
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn write(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        (**self).write(buf)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), io::Error> {
+        (**self).read(buf)
+    }
+
+    fn transfer(&mut self, tx: &[u8], rx: &mut [u8]) -> Result<(), io::Error> {
+        (**self).transfer(tx, rx)
+    }
+
+    fn discard_input(&mut self) -> Result<(), io::Error> {
+        (**self).discard_input()
+    }
+
+    fn batch(&mut self, requests: &[(Vec<u8>, usize)]) -> Result<Vec<Vec<u8>>, io::Error> {
+        (**self).batch(requests)
+    }
+
+    fn note(&mut self, msg: &str) {
+        (**self).note(msg)
+    }
+
+    fn set_speed(&mut self, speed_hz: u32) -> Result<u32, io::Error> {
+        (**self).set_speed(speed_hz)
+    }
+}
+
+// Transport over a Linux spidev device, e.g. /dev/spidev0.0.
+pub struct SpiTransport {
+    spi: Spidev,
+    path: String,
+}
+
+impl SpiTransport {
+    pub fn open(path: &str, options: &SpidevOptions) -> Result<SpiTransport, io::Error> {
+        let mut spi = try!(Spidev::open(path));
+        try!(spi.configure(options));
+        Ok(SpiTransport {
+            spi: spi,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl Transport for SpiTransport {
+    fn write(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        self.spi.write_all(buf)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), io::Error> {
+        self.spi.read_exact(buf)
+    }
+
+    fn transfer(&mut self, tx: &[u8], rx: &mut [u8]) -> Result<(), io::Error> {
+        assert_eq!(tx.len(), rx.len());
+        let mut transfer = SpidevTransfer::write(tx);
+        try!(self.spi.transfer(&mut transfer));
+        rx.copy_from_slice(&transfer.rx_buf.unwrap());
+        Ok(())
+    }
+    // All requests and responses are done with a single ioctl. Note that
+    // spidev 0.2 doesn't allow setting a delay between transfers, so the
+    // bytes are sent at the configured clock speed without pauses.
+    fn batch(&mut self, requests: &[(Vec<u8>, usize)]) -> Result<Vec<Vec<u8>>, io::Error> {
+        let mut transfers = Vec::new();
+        for &(ref request, response_length) in requests {
+            transfers.push(SpidevTransfer::write(request));
+            if response_length > 0 {
+                transfers.push(SpidevTransfer::read(response_length));
+            }
+        }
+        try!(self.spi.transfer_multiple(&transfers));
+
+        let mut responses = Vec::new();
+        let mut transfers = transfers.into_iter();
+        for &(_, response_length) in requests {
+            transfers.next(); // request
+            if response_length > 0 {
+                let transfer = transfers.next().unwrap();
+                responses.push(transfer.rx_buf.unwrap().into_vec());
+            } else {
+                responses.push(Vec::new());
+            }
+        }
+        Ok(responses)
+    }
+
+    fn set_speed(&mut self, speed_hz: u32) -> Result<u32, io::Error> {
+        // Spidev doesn't give access to its file descriptor. The speed is a
+        // setting of the device, so it can be read through another one.
+        let previous = try!(spidevioctl::get_max_speed_hz(try!(File::open(&self.path))
+            .as_raw_fd()));
+        let mut options = SpidevOptions::new();
+        options.max_speed_hz(speed_hz);
+        try!(self.spi.configure(&options));
+        Ok(previous)
+    }
+}
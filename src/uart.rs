@@ -0,0 +1,122 @@
+use std::io;
+use std::io::prelude::*;
+use std::time;
+
+use serial::{self, SerialPort, SystemPort};
+
+use transport::Transport;
+
+
+pub const DEFAULT_BAUD_RATE: u32 = 9600;
+const TIMEOUT: u64 = 100; // 100ms
+
+// Byte returned by transfer() when the peripheral didn't send anything, like
+// an idle SPI bus.
+const IDLE_BYTE: u8 = 0x00;
+
+// Transport over a UART, e.g. a USB-serial adapter at /dev/ttyUSB0. The AVR
+// answers commands with the same frames as on SPI.
+pub struct UartTransport {
+    port: SystemPort,
+}
+
+impl UartTransport {
+    pub fn open(path: &str, baud_rate: u32) -> Result<UartTransport, io::Error> {
+        let mut port = try!(serial::open(path));
+        try!(port.reconfigure(&|settings| {
+            try!(settings.set_baud_rate(serial::BaudRate::from_speed(baud_rate as usize)));
+            settings.set_char_size(serial::Bits8);
+            settings.set_parity(serial::ParityNone);
+            settings.set_stop_bits(serial::Stop1);
+            settings.set_flow_control(serial::FlowNone);
+            Ok(())
+        }));
+        try!(port.set_timeout(time::Duration::from_millis(TIMEOUT)));
+        Ok(UartTransport { port: port })
+    }
+}
+
+impl Transport for UartTransport {
+    fn write(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        self.port.write_all(buf)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), io::Error> {
+        self.port.read_exact(buf)
+    }
+
+    fn transfer(&mut self, tx: &[u8], rx: &mut [u8]) -> Result<(), io::Error> {
+        assert_eq!(tx.len(), rx.len());
+        try!(self.port.write_all(tx));
+
+        // A UART isn't clocked by the host, so there may be nothing to read.
+        let mut received = 0;
+        while received < rx.len() {
+            match self.port.read(&mut rx[received..]) {
+                Ok(0) => break,
+                Ok(n) => received += n,
+                Err(ref err) if err.kind() == io::ErrorKind::TimedOut => break,
+                Err(err) => return Err(err),
+            }
+        }
+        for c in rx[received..].iter_mut() {
+            *c = IDLE_BYTE;
+        }
+        Ok(())
+    }
+
+    fn discard_input(&mut self) -> Result<(), io::Error> {
+        let mut buf: [u8; 16] = [0; 16];
+        loop {
+            match self.port.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {}
+                Err(ref err) if err.kind() == io::ErrorKind::TimedOut => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_uart_pty() {
+    use std::{ffi, fs, ptr, thread};
+    use std::os::unix::io::FromRawFd;
+    use libc;
+
+    use emulator::Emulator;
+    use peripheral::*;
+
+    // Run the emulator on the master side of a pseudo-terminal.
+    let mut master_fd = 0;
+    let mut slave_fd = 0;
+    let mut name = [0 as libc::c_char; 64];
+    let result = unsafe {
+        libc::openpty(&mut master_fd,
+                      &mut slave_fd,
+                      name.as_mut_ptr(),
+                      ptr::null(),
+                      ptr::null())
+    };
+    assert_eq!(result, 0);
+    let slave_path = unsafe { ffi::CStr::from_ptr(name.as_ptr()) }.to_str().unwrap().to_string();
+    let mut master = unsafe { fs::File::from_raw_fd(master_fd) };
+    thread::spawn(move || {
+        let mut emulator = Emulator::new();
+        let mut buf: [u8; 1] = [0; 1];
+        while let Ok(1) = master.read(&mut buf) {
+            let response = emulator.receive_uart(buf[0]);
+            if master.write_all(&response).is_err() {
+                break;
+            }
+        }
+    });
+
+    let transport = UartTransport::open(&slave_path, DEFAULT_BAUD_RATE).unwrap();
+    let mut peripheral = Peripheral::new(transport);
+    unsafe { libc::close(slave_fd) };
+    peripheral.resync().unwrap();
+    assert_eq!(peripheral.read_number(CMD_TEST, 2).unwrap(), 0xabcd);
+    peripheral.write_number(CMD_COLOR, 4, 0x4148ffff).unwrap();
+    assert_eq!(peripheral.read_number(CMD_COLOR, 4).unwrap(), 0x4148ffff);
+}